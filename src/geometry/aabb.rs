@@ -100,6 +100,38 @@ impl AABB {
         }
     }
 
+    ///
+    /// Center point of the box
+    pub fn center(&self) -> nalgebra::Point3<f32> {
+        nalgebra::center(&self.lower, &self.upper)
+    }
+
+    ///
+    /// Surface area of the box, zero if the box is empty
+    pub fn surface_area(&self) -> f32 {
+        let d = self.upper - self.lower;
+        if d.x >= 0.0 && d.y >= 0.0 && d.z >= 0.0 {
+            2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+        } else {
+            0.0
+        }
+    }
+
+    ///
+    /// Bounding box of this box after being transformed
+    pub fn transform(&self, transform: &nalgebra::Transform3<f32>) -> Self {
+        let mut corners = Vec::new();
+        for &x in &[self.lower.x, self.upper.x] {
+            for &y in &[self.lower.y, self.upper.y] {
+                for &z in &[self.lower.z, self.upper.z] {
+                    let p = transform * nalgebra::Point3::new(x, y, z);
+                    corners.push(AABB { lower: p, upper: p });
+                }
+            }
+        }
+        union(&corners)
+    }
+
     ///
     /// Compute the intersection of a ray against a bounding box
     pub fn intersect(&self, ray: Ray) -> Interval {
@@ -153,3 +185,30 @@ pub fn union(boxes: &[AABB]) -> AABB {
         upper: upper,
     }
 }
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// A transformed box must enclose every transformed corner, and no more
+    #[test]
+    fn transformed_bounds() {
+        let unit = AABB {
+            lower: nalgebra::Point3::new(-1.0, -1.0, 0.0),
+            upper: nalgebra::Point3::new(1.0, 1.0, 2.0),
+        };
+        let rotation = nalgebra::Rotation3::from_axis_angle(&nalgebra::Vector3::z_axis(), std::f32::consts::FRAC_PI_4);
+        let transform = nalgebra::Transform3::from_matrix_unchecked(
+            nalgebra::Matrix4::new_translation(&nalgebra::Vector3::new(5.0, 0.0, 0.0)) * rotation.to_homogeneous(),
+        );
+        let bounds = unit.transform(&transform);
+        let r = std::f32::consts::SQRT_2;
+        assert!((bounds.lower - nalgebra::Point3::new(5.0 - r, -r, 0.0)).norm() < 1e-5);
+        assert!((bounds.upper - nalgebra::Point3::new(5.0 + r, r, 2.0)).norm() < 1e-5);
+    }
+}
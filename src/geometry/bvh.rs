@@ -4,7 +4,20 @@ use super::*;
 // Public data types
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 
-const NODE_SIZE: usize = 4;
+pub const NODE_SIZE: usize = 4;
+const SAH_BINS: usize = 16;
+
+///
+/// Strategy used to group the triangles of a mesh into tree nodes
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BuildStrategy {
+    /// Binned surface area heuristic
+    Sah,
+    /// Split at the median centroid along the widest axis
+    Median,
+    /// Group the triangles in file order
+    Naive,
+}
 
 ///
 /// A node of the BVH
@@ -14,7 +27,6 @@ pub enum Node {
     Empty,
     Leaf(Box<TriangleBundle>),
     Branch(Box<BVH>),
-    //Instance(Box<nalgebra::Transform3<f32>>, Box<BVH>),
 }
 
 ///
@@ -25,6 +37,21 @@ pub struct BVH {
     children: [Node; NODE_SIZE],
 }
 
+///
+/// Parse a strategy name, as given on the command line
+impl std::str::FromStr for BuildStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sah" => Ok(BuildStrategy::Sah),
+            "median" => Ok(BuildStrategy::Median),
+            "naive" => Ok(BuildStrategy::Naive),
+            _ => Err(format!("Unknown BVH build strategy: {}", s)),
+        }
+    }
+}
+
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 // Tree construction
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -43,22 +70,32 @@ impl BVH {
 
     ///
     /// Constructor
-    pub fn build_mesh(elements: &[(TriangleBundle, AABB)]) -> Self {
-        let leaves = Self::build_leaves(elements);
-        Self::build_branches(&leaves)
-    }
-
-    /*///
-    /// Constructor
-    pub fn build_world(elements: &[Mesh]) -> Self {
-        let leaves = Self::build_leaves(elements);
-        Self::build_branches(&leaves)
-        let mut bundles = Vec::new();
-        for m in meshes {
-            let b = m.bounds();
-            bundles.push((m, b));
+    pub fn build_mesh(vertices: &[Vertex], faces: &[Triangle], strategy: BuildStrategy) -> Self {
+        match strategy {
+            BuildStrategy::Naive => {
+                let mut bundles = Vec::new();
+                for c in faces.chunks(BUNDLE_SIZE) {
+                    let bundle = TriangleBundle::new(vertices, c);
+                    let bounds = AABB::from_faces(vertices, c);
+                    bundles.push((bundle, bounds));
+                }
+                let leaves = Self::build_leaves(&bundles);
+                Self::build_branches(&leaves)
+            }
+            _ => {
+                let mut primitives: Vec<Primitive> = faces
+                    .iter()
+                    .enumerate()
+                    .map(|(i, f)| Primitive::new(i, AABB::from_faces(vertices, &[*f])))
+                    .collect();
+                if primitives.is_empty() {
+                    Self::empty()
+                } else {
+                    Self::build_sorted(vertices, faces, &mut primitives, strategy)
+                }
+            }
         }
-    }*/
+    }
 
     ///
     /// First stage: turn the leaves into nodes
@@ -101,6 +138,32 @@ impl BVH {
             Self::build_branches(&leaves)
         }
     }
+
+    ///
+    /// Spatially sorted construction: split the triangles into up to NODE_SIZE groups,
+    /// turning the small groups into leaves and recursing on the rest
+    fn build_sorted(
+        vertices: &[Vertex],
+        faces: &[Triangle],
+        primitives: &mut [Primitive],
+        strategy: BuildStrategy,
+    ) -> Self {
+        let groups = partition_node(primitives, BUNDLE_SIZE, strategy);
+        let mut node = Self::empty();
+        for (i, &(start, finish)) in groups.iter().enumerate() {
+            let group = &mut primitives[start..finish];
+            let bounds: Vec<AABB> = group.iter().map(|p| p.bounds).collect();
+            node.bounds[i] = union(&bounds);
+            if group.len() <= BUNDLE_SIZE {
+                let leaf: Vec<Triangle> = group.iter().map(|p| faces[p.index]).collect();
+                node.children[i] = Node::Leaf(Box::new(TriangleBundle::new(vertices, &leaf)));
+            } else {
+                let branch = Self::build_sorted(vertices, faces, group, strategy);
+                node.children[i] = Node::Branch(Box::new(branch));
+            }
+        }
+        node
+    }
 }
 
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -130,12 +193,6 @@ impl BVH {
                             nearest_hit = hit;
                         }
                     }
-                    /*Node::Instance(transform, tree) => {
-                        let hit = tree.intersect(ray);
-                        if hit.distance < nearest_hit.distance {
-                            nearest_hit = hit;
-                        }
-                    }*/
                 }
             }
         }
@@ -144,3 +201,223 @@ impl BVH {
         nearest_hit
     }
 }
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Spatial partitioning, shared by the mesh and world trees
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// An element waiting to be placed in a tree, referenced by its index in the input array
+pub struct Primitive {
+    pub index: usize,
+    pub bounds: AABB,
+    pub centroid: nalgebra::Point3<f32>,
+}
+
+impl Primitive {
+    pub fn new(index: usize, bounds: AABB) -> Self {
+        Primitive {
+            index,
+            bounds,
+            centroid: bounds.center(),
+        }
+    }
+}
+
+///
+/// Split the primitives into up to NODE_SIZE contiguous groups, each one becoming a child of the node.
+/// The biggest group is split until the node is full or every group fits in a leaf of `leaf_size` elements.
+pub fn partition_node(
+    primitives: &mut [Primitive],
+    leaf_size: usize,
+    strategy: BuildStrategy,
+) -> Vec<(usize, usize)> {
+    let mut groups = vec![(0, primitives.len())];
+    while groups.len() < NODE_SIZE {
+        let (index, &(start, finish)) = groups
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (start, finish))| finish - start)
+            .unwrap();
+        if finish - start <= leaf_size {
+            break;
+        }
+        let middle = start + split(&mut primitives[start..finish], leaf_size, strategy);
+        groups[index] = (start, middle);
+        groups.push((middle, finish));
+    }
+    groups
+}
+
+///
+/// Reorder the primitives in two halves, returning the index of the first element of the second half
+fn split(primitives: &mut [Primitive], leaf_size: usize, strategy: BuildStrategy) -> usize {
+    let centroids: Vec<AABB> = primitives
+        .iter()
+        .map(|p| AABB {
+            lower: p.centroid,
+            upper: p.centroid,
+        })
+        .collect();
+    let centroid_bounds = union(&centroids);
+
+    match strategy {
+        BuildStrategy::Sah => split_sah(primitives, &centroid_bounds, leaf_size)
+            .unwrap_or_else(|| split_median(primitives, &centroid_bounds)),
+        BuildStrategy::Median => split_median(primitives, &centroid_bounds),
+        BuildStrategy::Naive => primitives.len() / 2,
+    }
+}
+
+///
+/// Split at the median centroid along the widest axis
+fn split_median(primitives: &mut [Primitive], centroid_bounds: &AABB) -> usize {
+    let axis = (centroid_bounds.upper - centroid_bounds.lower).imax();
+    let middle = primitives.len() / 2;
+    primitives.select_nth_unstable_by(middle, |a, b| {
+        a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    middle
+}
+
+///
+/// Split along the cheapest plane according to the binned surface area heuristic.
+/// The cost of each side is measured in leaves, since that's the unit the tree stores.
+/// Returns None if the centroids are too close together to be binned.
+fn split_sah(primitives: &mut [Primitive], centroid_bounds: &AABB, leaf_size: usize) -> Option<usize> {
+    let extent = centroid_bounds.upper - centroid_bounds.lower;
+    let mut best: Option<(f32, usize, usize)> = None;
+
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+
+        // Drop every primitive into its bin
+        let scale = (SAH_BINS as f32) / extent[axis];
+        let bin_of = |p: &Primitive| {
+            let b = ((p.centroid[axis] - centroid_bounds.lower[axis]) * scale) as usize;
+            usize::min(b, SAH_BINS - 1)
+        };
+        let mut counts = [0usize; SAH_BINS];
+        let mut bounds = [AABB::empty(); SAH_BINS];
+        for p in primitives.iter() {
+            let b = bin_of(p);
+            counts[b] += 1;
+            bounds[b] = union(&[bounds[b], p.bounds]);
+        }
+
+        // Sweep from the right to get the cost of every right half
+        let mut right_area = [0.0; SAH_BINS];
+        let mut right_count = [0usize; SAH_BINS];
+        let mut acc_bounds = AABB::empty();
+        let mut acc_count = 0;
+        for b in (1..SAH_BINS).rev() {
+            acc_bounds = union(&[acc_bounds, bounds[b]]);
+            acc_count += counts[b];
+            right_area[b] = acc_bounds.surface_area();
+            right_count[b] = acc_count;
+        }
+
+        // Sweep from the left and evaluate every split plane
+        let mut acc_bounds = AABB::empty();
+        let mut acc_count = 0;
+        for b in 1..SAH_BINS {
+            acc_bounds = union(&[acc_bounds, bounds[b - 1]]);
+            acc_count += counts[b - 1];
+            if acc_count == 0 || right_count[b] == 0 {
+                continue;
+            }
+            let cost = acc_bounds.surface_area() * acc_count.div_ceil(leaf_size) as f32
+                + right_area[b] * right_count[b].div_ceil(leaf_size) as f32;
+            let better = match best {
+                Some((best_cost, _, _)) => cost < best_cost,
+                None => true,
+            };
+            if better {
+                best = Some((cost, axis, b));
+            }
+        }
+    }
+
+    // Partition the primitives around the chosen plane
+    let (_, axis, plane) = best?;
+    let scale = (SAH_BINS as f32) / extent[axis];
+    let mut middle = 0;
+    for i in 0..primitives.len() {
+        let b = ((primitives[i].centroid[axis] - centroid_bounds.lower[axis]) * scale) as usize;
+        if usize::min(b, SAH_BINS - 1) < plane {
+            primitives.swap(i, middle);
+            middle += 1;
+        }
+    }
+    Some(middle)
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    ///
+    /// Small random triangles scattered in the unit cube, with a different material each so hits can be told apart
+    fn triangle_soup(rng: &mut SmallRng, count: u32) -> (Vec<Vertex>, Vec<Triangle>) {
+        let mut vertices = Vec::new();
+        let mut faces = Vec::new();
+        for i in 0..count {
+            let center = nalgebra::Point3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+            for _ in 0..3 {
+                let offset = nalgebra::Vector3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 0.2;
+                vertices.push(Vertex {
+                    coords: center + offset,
+                    normal: nalgebra::Vector3::z(),
+                    uv: nalgebra::zero(),
+                });
+            }
+            faces.push(Triangle {
+                v1: 3 * i,
+                v2: 3 * i + 1,
+                v3: 3 * i + 2,
+                material: i,
+            });
+        }
+        (vertices, faces)
+    }
+
+    ///
+    /// Every build strategy must find the same nearest hit
+    #[test]
+    fn strategies_find_the_same_hits() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let (vertices, faces) = triangle_soup(&mut rng, 500);
+        let meshes: Vec<Mesh> = [BuildStrategy::Sah, BuildStrategy::Median, BuildStrategy::Naive]
+            .iter()
+            .map(|strategy| Mesh::new(vertices.clone(), faces.clone(), *strategy))
+            .collect();
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = nalgebra::Point3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 3.0
+                - nalgebra::Vector3::repeat(1.0);
+            let target = nalgebra::Point3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+            let ray = Ray {
+                origin,
+                direction: (target - origin).normalize(),
+            };
+            let expected = meshes[0].intersect(ray);
+            for mesh in &meshes[1..] {
+                let hit = mesh.intersect(ray);
+                assert!(hit.distance == expected.distance && hit.material == expected.material);
+            }
+            if expected.distance.is_finite() {
+                hits += 1;
+            }
+        }
+        assert!(hits > 500, "only {} rays hit something", hits);
+    }
+}
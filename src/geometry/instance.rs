@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// A placement of a shared mesh in the world
+#[derive(Clone)]
+pub struct Instance {
+    pub mesh: Arc<Mesh>,
+    pub material: u32,
+    pub transform: nalgebra::Transform3<f32>,
+    inverse: nalgebra::Transform3<f32>,
+    normal_matrix: nalgebra::Matrix3<f32>,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Instance {
+    ///
+    /// Place a mesh in the world with an object-to-world transform, which must be invertible
+    pub fn new(mesh: Arc<Mesh>, material: u32, transform: nalgebra::Transform3<f32>) -> Self {
+        let inverse = transform
+            .try_inverse()
+            .expect("Instance transforms must be invertible");
+
+        // Normals are transformed by the inverse transpose of the linear part
+        let normal_matrix = inverse.matrix().fixed_slice::<nalgebra::U3, nalgebra::U3>(0, 0).transpose();
+
+        Instance {
+            mesh,
+            material,
+            transform,
+            inverse,
+            normal_matrix,
+        }
+    }
+
+    ///
+    /// Bounding box in world space
+    pub fn bounds(&self) -> AABB {
+        self.mesh.bounds().transform(&self.transform)
+    }
+
+    ///
+    /// Intersect a world-space ray against the instance
+    pub fn intersect(&self, ray: Ray) -> MeshIntersection {
+        // The direction is not normalized, so that distances are the same in both spaces
+        let local_ray = Ray {
+            origin: self.inverse * ray.origin,
+            direction: self.inverse * ray.direction,
+        };
+
+        let hit = self.mesh.intersect(local_ray);
+        if hit.distance.is_finite() {
+            MeshIntersection {
+                distance: hit.distance,
+                material: self.material,
+                point: self.transform * hit.point,
+                normal: (self.normal_matrix * hit.normal).normalize(),
+                geometric_normal: (self.normal_matrix * hit.geometric_normal).normalize(),
+                uv: hit.uv,
+                instance: hit.instance,
+            }
+        } else {
+            hit
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// A single triangle in the XZ plane, tilted so that its normal is (1, 0, 1) / sqrt(2)
+    fn tilted_triangle() -> Arc<Mesh> {
+        let normal = nalgebra::Vector3::new(1.0, 0.0, 1.0).normalize();
+        let vertex = |x, y, z| Vertex {
+            coords: nalgebra::Point3::new(x, y, z),
+            normal,
+            uv: nalgebra::zero(),
+        };
+        let vertices = vec![vertex(1.0, -1.0, -1.0), vertex(-1.0, -1.0, 1.0), vertex(0.0, 1.0, 0.0)];
+        let faces = vec![Triangle {
+            v1: 0,
+            v2: 2,
+            v3: 1,
+            material: 0,
+        }];
+        Arc::new(Mesh::new(vertices, faces, BuildStrategy::Sah))
+    }
+
+    ///
+    /// Hits must be reported in world space, with the normals kept perpendicular under non-uniform scaling
+    #[test]
+    fn transformed_hits() {
+        let scale = nalgebra::Vector3::new(2.0, 1.0, 1.0);
+        let translation = nalgebra::Vector3::new(0.0, 0.0, 3.0);
+        let transform = nalgebra::Transform3::from_matrix_unchecked(
+            nalgebra::Matrix4::new_translation(&translation) * nalgebra::Matrix4::new_nonuniform_scaling(&scale),
+        );
+        let instance = Instance::new(tilted_triangle(), 7, transform);
+
+        let ray = Ray {
+            origin: nalgebra::Point3::new(0.0, 0.0, 10.0),
+            direction: -nalgebra::Vector3::z(),
+        };
+        let hit = instance.intersect(ray);
+        assert!(hit.material == 7);
+        assert!((hit.point - nalgebra::Point3::new(0.0, 0.0, 3.0)).norm() < 1e-5);
+        assert!((hit.distance - 7.0).abs() < 1e-5);
+
+        // The plane x + z = 0 becomes x / 2 + z = 0
+        let expected = nalgebra::Vector3::new(0.5, 0.0, 1.0).normalize();
+        assert!((hit.normal - expected).norm() < 1e-5);
+        assert!((hit.geometric_normal - expected).norm() < 1e-5);
+        assert!((instance.bounds().lower - nalgebra::Point3::new(-2.0, -1.0, 2.0)).norm() < 1e-5);
+        assert!((instance.bounds().upper - nalgebra::Point3::new(2.0, 1.0, 4.0)).norm() < 1e-5);
+    }
+}
@@ -17,6 +17,7 @@ use super::*;
 pub struct Vertex {
     pub coords: nalgebra::Point3<f32>,
     pub normal: nalgebra::Vector3<f32>,
+    pub uv: nalgebra::Vector2<f32>,
 }
 
 ///
@@ -34,6 +35,7 @@ pub struct Triangle {
 #[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
+    pub triangles: Vec<Triangle>,
     pub faces: BVH,
 }
 
@@ -44,6 +46,11 @@ pub struct MeshIntersection {
     pub material: u32,
     pub point: nalgebra::Point3<f32>,
     pub normal: nalgebra::Vector3<f32>,
+    /// Normal of the flat triangle, unlike the interpolated shading normal above
+    pub geometric_normal: nalgebra::Vector3<f32>,
+    pub uv: nalgebra::Vector2<f32>,
+    /// Index of the instance in the world, set by World::intersect
+    pub instance: u32,
 }
 
 impl MeshIntersection {
@@ -53,6 +60,9 @@ impl MeshIntersection {
             material: u32::MAX,
             point: nalgebra::geometry::Point::origin(),
             normal: nalgebra::zero(),
+            geometric_normal: nalgebra::zero(),
+            uv: nalgebra::zero(),
+            instance: u32::MAX,
         }
     }
 }
@@ -76,26 +86,25 @@ impl Triangle {
 impl Mesh {
     ///
     /// Create a new mesh from an array of vertices and an array of triangles
-    pub fn new(vertices: Vec<Vertex>, faces: Vec<Triangle>) -> Self {
+    pub fn new(vertices: Vec<Vertex>, faces: Vec<Triangle>, strategy: BuildStrategy) -> Self {
         // Build the acceleration structure
-        let mut bundles = Vec::new();
-        for c in faces.chunks(BUNDLE_SIZE) {
-            let foo = TriangleBundle::new(&vertices, &c.to_vec());
-            let bar = AABB::from_faces(&vertices, &c.to_vec());
-            bundles.push((foo, bar));
-        }
-        let tree = BVH::build_mesh(&bundles);
+        let tree = BVH::build_mesh(&vertices, &faces, strategy);
 
         // Done
         Mesh {
             vertices: vertices,
+            triangles: faces,
             faces: tree,
         }
     }
 
     ///
     /// Load a mesh from a PLY file
-    pub fn load_ply(filename: &Path, material: u32) -> Result<Self, std::io::Error> {
+    pub fn load_ply(
+        filename: &Path,
+        material: u32,
+        strategy: BuildStrategy,
+    ) -> Result<Self, std::io::Error> {
         println!("Loading mesh {}", filename.display());
         let f = File::open(filename)?;
         let file = BufReader::new(&f);
@@ -105,6 +114,8 @@ impl Mesh {
         let mut faces: Vec<Triangle> = Vec::new();
         let mut num_vertices: usize = 0;
         let mut num_faces: usize = 0;
+        let mut vertex_properties: Vec<String> = Vec::new();
+        let mut in_vertex_element = false;
 
         // Read the header
         loop {
@@ -117,29 +128,54 @@ impl Mesh {
                         break;
                     } else if (fields[0] == "element") && (fields[1] == "vertex") {
                         num_vertices = fields[2].parse::<usize>().unwrap();
+                        in_vertex_element = true;
                     } else if (fields[0] == "element") && (fields[1] == "face") {
                         num_faces = fields[2].parse::<usize>().unwrap();
+                        in_vertex_element = false;
+                    } else if fields[0] == "property" && in_vertex_element {
+                        vertex_properties.push(fields[fields.len() - 1].to_string());
                     }
                 }
             }
         }
 
+        // Columns of each vertex attribute, falling back to the "x y z nx ny nz" layout
+        let column = |names: &[&str], default: Option<usize>| {
+            vertex_properties
+                .iter()
+                .position(|p| names.contains(&p.as_str()))
+                .or(default)
+        };
+        let position_columns = [
+            column(&["x"], Some(0)).unwrap(),
+            column(&["y"], Some(1)).unwrap(),
+            column(&["z"], Some(2)).unwrap(),
+        ];
+        let normal_columns = [
+            column(&["nx"], Some(3)).unwrap(),
+            column(&["ny"], Some(4)).unwrap(),
+            column(&["nz"], Some(5)).unwrap(),
+        ];
+        let uv_columns = column(&["s", "u", "texture_u", "texture_s"], None)
+            .zip(column(&["t", "v", "texture_v", "texture_t"], None));
+
         // Read the vertices
         for _i in 0..num_vertices {
             match lines.next() {
                 None => break,
                 Some(line) => {
                     let foo = line.unwrap();
-                    let fields: Vec<&str> = foo.split(" ").collect();
-                    let vx = fields[0].parse::<f32>().unwrap();
-                    let vy = fields[1].parse::<f32>().unwrap();
-                    let vz = fields[2].parse::<f32>().unwrap();
-                    let nx = fields[3].parse::<f32>().unwrap();
-                    let ny = fields[4].parse::<f32>().unwrap();
-                    let nz = fields[5].parse::<f32>().unwrap();
+                    let fields: Vec<f32> = foo.split_whitespace().map(|f| f.parse::<f32>().unwrap()).collect();
+                    let [vx, vy, vz] = position_columns.map(|c| fields[c]);
+                    let [nx, ny, nz] = normal_columns.map(|c| fields[c]);
+                    let uv = match uv_columns {
+                        Some((u, v)) => nalgebra::Vector2::new(fields[u], fields[v]),
+                        None => nalgebra::zero(),
+                    };
                     vertices.push(Vertex {
                         coords: nalgebra::Point3::new(vx, vy, vz),
                         normal: nalgebra::Vector3::new(nx, ny, nz),
+                        uv,
                     });
                 }
             }
@@ -167,7 +203,7 @@ impl Mesh {
         }
 
         // Build the acceleration structure
-        Ok(Self::new(vertices, faces))
+        Ok(Self::new(vertices, faces, strategy))
     }
 
     ///
@@ -193,11 +229,16 @@ impl Mesh {
                 + hit.gamma * v3.coords.coords;
             let normal =
                 (hit.alpha * v1.normal + hit.beta * v2.normal + hit.gamma * v3.normal).normalize();
+            let geometric_normal = (v2.coords - v1.coords).cross(&(v3.coords - v1.coords)).normalize();
+            let uv = hit.alpha * v1.uv + hit.beta * v2.uv + hit.gamma * v3.uv;
                 MeshIntersection {
                 point: nalgebra::Point3::new(point.x, point.y, point.z),
                 normal: normal,
+                geometric_normal,
+                uv,
                 distance: hit.distance,
                 material: hit.face.material,
+                instance: u32::MAX,
             }
         } else {
             MeshIntersection::empty()
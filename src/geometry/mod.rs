@@ -4,6 +4,9 @@ pub use aabb::*;
 pub mod bvh;
 pub use bvh::*;
 
+pub mod instance;
+pub use instance::*;
+
 pub mod mesh;
 pub use mesh::*;
 
@@ -18,3 +21,6 @@ pub use triangle_bundle::*;
 
 pub mod util;
 pub use util::*;
+
+pub mod world;
+pub use world::*;
@@ -27,3 +27,42 @@ impl Interval {
         }
     }
 }
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Orthonormal frame around a surface normal
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub tangent: nalgebra::Vector3<f32>,
+    pub bitangent: nalgebra::Vector3<f32>,
+    pub normal: nalgebra::Vector3<f32>,
+}
+
+///
+/// [Duff et al.] Building an orthonormal basis, revisited
+/// http://jcgt.org/published/0006/01/01/
+impl Frame {
+    pub fn new(normal: nalgebra::Vector3<f32>) -> Self {
+        let sign = 1.0f32.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+        Frame {
+            tangent: nalgebra::Vector3::new(1.0 + sign * normal.x * normal.x * a, sign * b, -sign * normal.x),
+            bitangent: nalgebra::Vector3::new(b, sign + normal.y * normal.y * a, -normal.y),
+            normal,
+        }
+    }
+
+    ///
+    /// Convert a vector from local coordinates, where the normal is the Z axis, to world coordinates
+    pub fn local_to_world(&self, v: nalgebra::Vector3<f32>) -> nalgebra::Vector3<f32> {
+        v.x * self.tangent + v.y * self.bitangent + v.z * self.normal
+    }
+
+    ///
+    /// Convert a vector from world coordinates to local coordinates
+    pub fn world_to_local(&self, v: nalgebra::Vector3<f32>) -> nalgebra::Vector3<f32> {
+        nalgebra::Vector3::new(v.dot(&self.tangent), v.dot(&self.bitangent), v.dot(&self.normal))
+    }
+}
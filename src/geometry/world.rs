@@ -0,0 +1,195 @@
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// A node of the top-level tree
+#[derive(Clone)]
+pub enum WorldNode {
+    Empty,
+    Object(usize),
+    Branch(Box<WorldTree>),
+}
+
+///
+/// Top-level bounding volume hierarchy, whose leaves are indices into the instance list
+#[derive(Clone)]
+pub struct WorldTree {
+    bounds: [AABB; NODE_SIZE],
+    children: [WorldNode; NODE_SIZE],
+}
+
+///
+/// The whole world geometry: every instance plus a tree built over their bounding boxes
+pub struct World {
+    pub instances: Vec<Instance>,
+    tree: WorldTree,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tree construction
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl WorldTree {
+    ///
+    /// An empty tree
+    pub fn empty() -> Self {
+        WorldTree {
+            bounds: [AABB::empty(); NODE_SIZE],
+            children: [
+                WorldNode::Empty,
+                WorldNode::Empty,
+                WorldNode::Empty,
+                WorldNode::Empty,
+            ],
+        }
+    }
+
+    ///
+    /// Recursively group the instances, one instance per leaf
+    fn build(primitives: &mut [Primitive], strategy: BuildStrategy) -> Self {
+        let groups = partition_node(primitives, 1, strategy);
+        let mut node = Self::empty();
+        for (i, &(start, finish)) in groups.iter().enumerate() {
+            let group = &mut primitives[start..finish];
+            let bounds: Vec<AABB> = group.iter().map(|p| p.bounds).collect();
+            node.bounds[i] = union(&bounds);
+            if group.len() == 1 {
+                node.children[i] = WorldNode::Object(group[0].index);
+            } else {
+                node.children[i] = WorldNode::Branch(Box::new(Self::build(group, strategy)));
+            }
+        }
+        node
+    }
+}
+
+impl World {
+    ///
+    /// Build the top-level tree over a list of instances
+    pub fn new(instances: Vec<Instance>, strategy: BuildStrategy) -> Self {
+        let mut primitives: Vec<Primitive> = instances
+            .iter()
+            .enumerate()
+            .map(|(i, m)| Primitive::new(i, m.bounds()))
+            .collect();
+        let tree = if primitives.is_empty() {
+            WorldTree::empty()
+        } else {
+            WorldTree::build(&mut primitives, strategy)
+        };
+
+        World { instances, tree }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tree traversal
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl WorldTree {
+    fn intersect(&self, instances: &[Instance], ray: Ray) -> MeshIntersection {
+        let mut nearest_hit = MeshIntersection::empty();
+        for i in 0..NODE_SIZE {
+            let intersection = self.bounds[i].intersect(ray);
+            if intersection.start < nearest_hit.distance {
+                let hit = match &self.children[i] {
+                    WorldNode::Empty => continue,
+                    WorldNode::Object(index) => MeshIntersection {
+                        instance: *index as u32,
+                        ..instances[*index].intersect(ray)
+                    },
+                    WorldNode::Branch(tree) => tree.intersect(instances, ray),
+                };
+                if hit.distance < nearest_hit.distance {
+                    nearest_hit = hit;
+                }
+            }
+        }
+        nearest_hit
+    }
+}
+
+impl World {
+    ///
+    /// Find the nearest instance hit by the ray
+    pub fn intersect(&self, ray: Ray) -> MeshIntersection {
+        self.tree.intersect(&self.instances, ray)
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::Arc;
+
+    ///
+    /// Unit square in the XY plane, centered at the origin
+    fn square() -> Arc<Mesh> {
+        let vertex = |x, y| Vertex {
+            coords: nalgebra::Point3::new(x, y, 0.0),
+            normal: nalgebra::Vector3::z(),
+            uv: nalgebra::zero(),
+        };
+        let vertices = vec![vertex(-0.5, -0.5), vertex(0.5, -0.5), vertex(0.5, 0.5), vertex(-0.5, 0.5)];
+        let triangle = |v1, v2, v3| Triangle { v1, v2, v3, material: 0 };
+        Arc::new(Mesh::new(vertices, vec![triangle(0, 1, 2), triangle(0, 2, 3)], BuildStrategy::Sah))
+    }
+
+    ///
+    /// The tree must find the same nearest instance as testing every one of them, whatever the build strategy
+    #[test]
+    fn tree_matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(5);
+        let mesh = square();
+        let instances: Vec<Instance> = (0..200)
+            .map(|_| {
+                let translation = nalgebra::Vector3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 10.0;
+                let rotation = nalgebra::UnitQuaternion::from_euler_angles(rng.gen(), rng.gen(), rng.gen());
+                let matrix = nalgebra::Matrix4::new_translation(&translation)
+                    * rotation.to_homogeneous()
+                    * nalgebra::Matrix4::new_scaling(0.5 + rng.gen::<f32>());
+                Instance::new(mesh.clone(), 0, nalgebra::Transform3::from_matrix_unchecked(matrix))
+            })
+            .collect();
+        let rays: Vec<Ray> = (0..1000)
+            .map(|_| {
+                let origin = nalgebra::Point3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 14.0
+                    - nalgebra::Vector3::repeat(2.0);
+                let target = nalgebra::Point3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 10.0;
+                Ray {
+                    origin,
+                    direction: (target - origin).normalize(),
+                }
+            })
+            .collect();
+
+        for strategy in [BuildStrategy::Sah, BuildStrategy::Median, BuildStrategy::Naive].iter() {
+            let world = World::new(instances.clone(), *strategy);
+            let mut hits = 0;
+            for ray in &rays {
+                let mut expected = (f32::INFINITY, u32::MAX);
+                for (i, instance) in world.instances.iter().enumerate() {
+                    let distance = instance.intersect(*ray).distance;
+                    if distance < expected.0 {
+                        expected = (distance, i as u32);
+                    }
+                }
+                let hit = world.intersect(*ray);
+                assert!((hit.distance, hit.instance) == expected, "{:?}", strategy);
+                if hit.distance.is_finite() {
+                    hits += 1;
+                }
+            }
+            assert!(hits > 100, "only {} rays hit something", hits);
+        }
+    }
+}
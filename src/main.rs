@@ -11,12 +11,33 @@ struct ProgramOptions {
     width: usize,
     height: usize,
     max_bounces: usize,
+    rr_depth: usize,
     num_samples: usize,
+    bvh_strategy: geometry::BuildStrategy,
+    filter: tracer::Filter,
+    sampler: tracer::SamplerKind,
+    seed: u64,
+    exr_precision: tracer::ExrPrecision,
+    aovs: Vec<tracer::Aov>,
+    tone_mapping: tracer::ToneMapping,
+    snapshot_samples: usize,
+    snapshot_seconds: f32,
+    checkpoint_file: Option<std::path::PathBuf>,
+    checkpoint_seconds: f32,
+    resume: bool,
+    adaptive_threshold: f32,
+    adaptive_min_samples: usize,
+    time_limit: f32,
+    target_error: f32,
     scene_file: std::path::PathBuf,
     camera_file: std::path::PathBuf,
     image_file: std::path::PathBuf,
 }
 
+///
+/// Number of strata of the stratified sampler when the render has no fixed number of samples
+const OPEN_ENDED_STRATA: usize = 64;
+
 ///
 /// Parse the command line arguments
 fn parse_options() -> ProgramOptions {
@@ -30,7 +51,7 @@ fn parse_options() -> ProgramOptions {
                 .short("s")
                 .long("num-samples")
                 .value_name("SAMPLES")
-                .help("Number of samples to trace - More samples, more quality")
+                .help("Number of samples to trace - More samples, more quality. With a time limit or a target error, the most to trace")
                 .takes_value(true),
         )
         .arg(
@@ -54,7 +75,146 @@ fn parse_options() -> ProgramOptions {
                 .short("b")
                 .long("max-bounces")
                 .value_name("BOUNCES")
-                .help("Maximum number of bounces per ray")
+                .help("Maximum number of bounces per ray, or 0 for unlimited")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("rr-depth")
+                .long("rr-depth")
+                .value_name("BOUNCES")
+                .help("Number of bounces before Russian roulette can end a path")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("bvh")
+                .long("bvh")
+                .value_name("STRATEGY")
+                .help("BVH construction strategy")
+                .possible_values(&["sah", "median", "naive"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("filter")
+                .long("filter")
+                .value_name("FILTER")
+                .help("Pixel reconstruction filter")
+                .possible_values(&["box", "tent", "gaussian", "mitchell"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("sampler")
+                .long("sampler")
+                .value_name("SAMPLER")
+                .help("Sample generator for the pixel, light and BSDF sampling")
+                .possible_values(&["random", "stratified", "halton", "sobol"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed of the random numbers - The same seed always renders the same image")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("exr-precision")
+                .long("exr-precision")
+                .value_name("PRECISION")
+                .help("Floating point precision of OpenEXR output")
+                .possible_values(&["half", "float"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("aovs")
+                .long("aovs")
+                .value_name("AOVS")
+                .help("Comma-separated output variables to render along with the image")
+                .possible_values(&["albedo", "normal", "depth", "position", "material-id", "mesh-id"])
+                .use_delimiter(true)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tonemap")
+                .long("tonemap")
+                .value_name("OPERATOR")
+                .help("Tone mapping operator for PNG output")
+                .possible_values(&["none", "reinhard", "filmic", "aces"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("exposure")
+                .long("exposure")
+                .value_name("EV")
+                .help("Exposure compensation for PNG output, in stops")
+                .allow_hyphen_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("dither")
+                .long("dither")
+                .help("Dither PNG output to avoid banding"),
+        )
+        .arg(
+            clap::Arg::with_name("snapshot-samples")
+                .long("snapshot-samples")
+                .value_name("SAMPLES")
+                .help("Write the image so far every this many samples, or 0 to never do it")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("snapshot-seconds")
+                .long("snapshot-seconds")
+                .value_name("SECONDS")
+                .help("Write the image so far every this many seconds, or 0 to never do it")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("FILE")
+                .help("Save the render state to this file every few seconds, to resume it after an interruption")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("checkpoint-seconds")
+                .long("checkpoint-seconds")
+                .value_name("SECONDS")
+                .requires("checkpoint")
+                .help("Save the render state every this many seconds - 60 by default")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("resume")
+                .long("resume")
+                .requires("checkpoint")
+                .help("Continue the render saved in the checkpoint file"),
+        )
+        .arg(
+            clap::Arg::with_name("adaptive-threshold")
+                .long("adaptive-threshold")
+                .value_name("ERROR")
+                .help("Stop sampling pixels whose relative error is below this, or 0 to sample every pixel")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("adaptive-min-samples")
+                .long("adaptive-min-samples")
+                .value_name("SAMPLES")
+                .help("Number of samples taken in every pixel before adaptive sampling starts")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("time-limit")
+                .long("time-limit")
+                .value_name("SECONDS")
+                .help("Keep adding samples until the render would take longer than this, or 0 for no limit")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("target-error")
+                .long("target-error")
+                .value_name("ERROR")
+                .help("Keep adding samples until the mean relative error of the pixels is below this, or 0 for no target")
                 .takes_value(true),
         )
         .arg(
@@ -80,13 +240,24 @@ fn parse_options() -> ProgramOptions {
                 .short("o")
                 .long("output")
                 .value_name("FILE")
-                .help("Name of the resulting image")
+                .help("Name of the resulting image - Its extension chooses between PNG, HDR and OpenEXR")
                 .takes_value(true)
                 .required(true),
         )
         .get_matches();
 
     // Read the values
+    let time_limit = matches
+        .value_of("time-limit")
+        .unwrap_or("0")
+        .parse::<f32>()
+        .unwrap();
+    let target_error = matches
+        .value_of("target-error")
+        .unwrap_or("0")
+        .parse::<f32>()
+        .unwrap();
+    let open_ended = time_limit > 0.0 || target_error > 0.0;
     ProgramOptions {
         width: matches
             .value_of("width")
@@ -98,16 +269,93 @@ fn parse_options() -> ProgramOptions {
             .unwrap_or("1024")
             .parse::<usize>()
             .unwrap(),
-        num_samples: matches
-            .value_of("num-samples")
-            .unwrap_or("10")
-            .parse::<usize>()
-            .unwrap(),
+        num_samples: match matches.value_of("num-samples") {
+            Some(value) => value.parse::<usize>().unwrap(),
+            None if open_ended => usize::MAX,
+            None => 10,
+        },
         max_bounces: matches
             .value_of("max-bounces")
             .unwrap_or("4")
             .parse::<usize>()
             .unwrap(),
+        rr_depth: matches
+            .value_of("rr-depth")
+            .unwrap_or("3")
+            .parse::<usize>()
+            .unwrap(),
+        bvh_strategy: matches
+            .value_of("bvh")
+            .unwrap_or("sah")
+            .parse::<geometry::BuildStrategy>()
+            .unwrap(),
+        filter: matches
+            .value_of("filter")
+            .unwrap_or("box")
+            .parse::<tracer::Filter>()
+            .unwrap(),
+        sampler: matches
+            .value_of("sampler")
+            .unwrap_or("sobol")
+            .parse::<tracer::SamplerKind>()
+            .unwrap(),
+        seed: matches
+            .value_of("seed")
+            .unwrap_or("0")
+            .parse::<u64>()
+            .unwrap(),
+        exr_precision: matches
+            .value_of("exr-precision")
+            .unwrap_or("half")
+            .parse::<tracer::ExrPrecision>()
+            .unwrap(),
+        aovs: matches
+            .values_of("aovs")
+            .map(|values| values.map(|v| v.parse::<tracer::Aov>().unwrap()).collect())
+            .unwrap_or_default(),
+        tone_mapping: tracer::ToneMapping {
+            operator: matches
+                .value_of("tonemap")
+                .unwrap_or("aces")
+                .parse::<tracer::ToneMapper>()
+                .unwrap(),
+            exposure: matches
+                .value_of("exposure")
+                .unwrap_or("0")
+                .parse::<f32>()
+                .unwrap(),
+            dither: matches.is_present("dither"),
+            srgb: true,
+        },
+        snapshot_samples: matches
+            .value_of("snapshot-samples")
+            .unwrap_or("0")
+            .parse::<usize>()
+            .unwrap(),
+        snapshot_seconds: matches
+            .value_of("snapshot-seconds")
+            .unwrap_or("0")
+            .parse::<f32>()
+            .unwrap(),
+        checkpoint_file: matches.value_of("checkpoint").map(std::path::PathBuf::from),
+        checkpoint_seconds: matches
+            .value_of("checkpoint-seconds")
+            .unwrap_or("60")
+            .parse::<f32>()
+            .unwrap(),
+        resume: matches.is_present("resume"),
+        adaptive_threshold: matches
+            .value_of("adaptive-threshold")
+            .unwrap_or("0")
+            .parse::<f32>()
+            .unwrap(),
+        adaptive_min_samples: matches
+            .value_of("adaptive-min-samples")
+            .unwrap_or("16")
+            .parse::<usize>()
+            .unwrap(),
+        time_limit,
+        target_error,
         scene_file: std::path::PathBuf::from(matches.value_of("input").unwrap_or("").to_string()),
         camera_file: std::path::PathBuf::from(matches.value_of("camera").unwrap_or("").to_string()),
         image_file: std::path::PathBuf::from(matches.value_of("output").unwrap_or("").to_string()),
@@ -126,6 +374,54 @@ pub fn pretty_time(t: std::time::Duration) -> String {
     format!("{}:{:02}:{:02}.{}", hours, minutes, seconds, nanos)
 }
 
+///
+/// Write the image and its output variables, as they are after the samples accumulated so far. Adaptive renders
+/// add the number of samples taken in each pixel.
+fn save_image(
+    options: &ProgramOptions,
+    film: &tracer::Film,
+    aov_film: &tracer::AovFilm,
+    stats: &tracer::PixelStats,
+) -> Result<(), std::io::Error> {
+    let fb = film.image();
+    let aov_images = aov_film.images();
+    let sample_counts = stats.sample_counts();
+    let mut layers = vec![("beauty", &fb)];
+    layers.extend(aov_images.iter().map(|(name, image)| (*name, image)));
+    if options.adaptive_threshold > 0.0 {
+        layers.push(("samples", &sample_counts));
+    }
+    tracer::image2d::save_atomically(&layers, &options.image_file, options.exr_precision, &options.tone_mapping)
+}
+
+///
+/// Identify everything that changes the accumulated samples: the scene and camera files, and the options
+/// used to render them. Meshes and textures are not included. The number of samples is left out so that a
+/// render can be resumed to add more, except for the stratified sampler, whose strata depend on it.
+fn render_fingerprint(options: &ProgramOptions, sampler: &tracer::SamplerConfig) -> Result<u64, std::io::Error> {
+    let scene = std::fs::read(&options.scene_file)?;
+    let camera = std::fs::read(&options.camera_file)?;
+    let strata = match sampler.kind {
+        tracer::SamplerKind::Stratified => sampler.samples_per_pixel,
+        _ => 0,
+    };
+    let settings = format!(
+        "{}x{} bounces={} rr={} strata={} filter={:?} sampler={:?} seed={} aovs={:?} adaptive={}/{}",
+        options.width,
+        options.height,
+        options.max_bounces,
+        options.rr_depth,
+        strata,
+        options.filter,
+        options.sampler,
+        options.seed,
+        options.aovs,
+        options.adaptive_threshold,
+        options.adaptive_min_samples,
+    );
+    Ok(tracer::fingerprint(&[&scene, &camera, settings.as_bytes()]))
+}
+
 ///
 ///
 fn main() {
@@ -138,31 +434,145 @@ fn main() {
     let load_start = std::time::Instant::now();
     let options = parse_options();
     println!("Loading scene...");
-    let scene = tracer::Scene::from_json(&options.scene_file).unwrap();
+    let scene = tracer::Scene::from_json(&options.scene_file, options.bvh_strategy).unwrap();
     println!("Loading camera...");
     let mut camera =
         tracer::Camera::from_json(&options.camera_file, options.width, options.height).unwrap();
+    camera.focus(&scene);
     let load_time = load_start.elapsed();
 
     // Render the scene
     let render_start = std::time::Instant::now();
-    let mut fb = tracer::image2d::new(options.width, options.height);
-    for i in 0..options.num_samples {
-        println!("Rendering sample {}/{}", i + 1, options.num_samples);
-        let sampling = tracer::sample(&scene, &mut camera, options.max_bounces);
-        tracer::image2d::accum(&mut fb, &sampling);
+    let mut film = tracer::Film::new(options.width, options.height, options.filter);
+    let mut aov_film = tracer::AovFilm::new(options.width, options.height, &options.aovs);
+    let mut stats = tracer::PixelStats::new(options.width, options.height);
+    let sampler = tracer::SamplerConfig {
+        kind: options.sampler,
+        // Without a fixed number of samples, the stratified sampler repeats rounds of this many strata
+        samples_per_pixel: if options.num_samples != usize::MAX {
+            options.num_samples
+        } else {
+            OPEN_ENDED_STRATA
+        },
+        seed: options.seed,
+    };
+    let fingerprint = render_fingerprint(&options, &sampler).unwrap();
+    let mut first_sample = 0;
+    if options.resume {
+        let checkpoint_file = options.checkpoint_file.as_ref().unwrap();
+        let checkpoint = match tracer::Checkpoint::load(checkpoint_file, fingerprint, &mut film, &mut aov_film, &mut stats) {
+            Ok(checkpoint) => checkpoint,
+            Err(error) => {
+                eprintln!("Can't resume from {}: {}", checkpoint_file.display(), error);
+                std::process::exit(1);
+            }
+        };
+        first_sample = checkpoint.samples_done;
+        println!("Resuming after {} samples", first_sample);
+    }
+    let mut active = ndarray::Array2::from_elem((options.height, options.width), true);
+    if options.adaptive_threshold > 0.0 && first_sample >= options.adaptive_min_samples {
+        active = stats.unconverged(options.adaptive_threshold);
+    }
+    let mut last_snapshot = std::time::Instant::now();
+    let mut last_checkpoint = std::time::Instant::now();
+    let budget = tracer::RenderBudget {
+        max_samples: options.num_samples,
+        time_limit: options.time_limit,
+        target_error: options.target_error,
+    };
+    let mut done = first_sample;
+    while done < options.num_samples {
+        let i = done;
+        if options.num_samples != usize::MAX {
+            println!("Rendering sample {}/{}", i + 1, options.num_samples);
+        } else {
+            println!("Rendering sample {}", i + 1);
+        }
+        let sampling = tracer::sample(
+            &scene,
+            &camera,
+            &sampler,
+            i,
+            options.max_bounces,
+            options.rr_depth,
+            &options.aovs,
+            &active,
+        );
+        film.splat_all(&sampling, &active);
+        aov_film.add_all(&sampling);
+        stats.add_all(&sampling);
+
+        // Once every pixel has its minimum, only the noisy ones keep being sampled
+        done += 1;
+        let mut finished = false;
+        if options.adaptive_threshold > 0.0 && done >= options.adaptive_min_samples {
+            active = stats.unconverged(options.adaptive_threshold);
+            let remaining = active.iter().filter(|a| **a).count();
+            if remaining == 0 {
+                println!("Every pixel converged after {} samples", done);
+                finished = true;
+            } else {
+                println!("{} pixels still above the error threshold", remaining);
+            }
+        }
+
+        let elapsed = render_start.elapsed().as_secs_f32();
+        let error = if options.target_error > 0.0 {
+            stats.mean_relative_error()
+        } else {
+            f32::INFINITY
+        };
+        let stop = budget.stop(done, done - first_sample, elapsed, error);
+        match stop {
+            Some(tracer::StopReason::TimeLimit) => println!("Time limit reached after {} samples", done),
+            Some(tracer::StopReason::TargetError) => {
+                println!("Target error reached after {} samples, at {}", done, error)
+            }
+            Some(tracer::StopReason::SampleCount) | None => {}
+        }
+        finished |= stop.is_some();
+
+        // Checkpoints, on their own interval so that they're written even without snapshots, and after the last
+        // pass so that a finished render can be resumed to add more samples
+        if let Some(checkpoint_file) = &options.checkpoint_file {
+            if finished || last_checkpoint.elapsed().as_secs_f32() >= options.checkpoint_seconds {
+                println!("Writing checkpoint after {} samples", done);
+                let checkpoint = tracer::Checkpoint {
+                    fingerprint,
+                    seed: options.seed,
+                    samples_done: done,
+                };
+                checkpoint.save(checkpoint_file, &film, &aov_film, &stats).unwrap();
+                last_checkpoint = std::time::Instant::now();
+            }
+        }
+        if finished {
+            break;
+        }
+
+        // Progressive snapshots, except after the last sample, which is written anyway
+        let snapshot_due = (options.snapshot_samples > 0 && done % options.snapshot_samples == 0)
+            || (options.snapshot_seconds > 0.0 && last_snapshot.elapsed().as_secs_f32() >= options.snapshot_seconds);
+        if snapshot_due {
+            println!("Writing snapshot after {} samples", done);
+            save_image(&options, &film, &aov_film, &stats).unwrap();
+            last_snapshot = std::time::Instant::now();
+        }
     }
-    tracer::image2d::scale(&mut fb, options.num_samples);
     let render_time = render_start.elapsed();
 
     // Write the resulting image
     let save_start = std::time::Instant::now();
     println!("Writing result");
-    tracer::image2d::save_png(&fb, &options.image_file).unwrap();
-    //tracer::image2d::save_hdr(&fb, &options.image_file).unwrap();
+    save_image(&options, &film, &aov_film, &stats).unwrap();
     let save_time = save_start.elapsed();
 
-    // Print the timing results
+    // Print the sampling and timing results
+    let mean_samples = stats.count.mean().unwrap_or(0.0);
+    println!("Sampling results:");
+    println!("\tSamples ->        {} ({} per pixel on average)", done, mean_samples);
+    println!("\tRelative error -> {}", stats.mean_relative_error());
     println!("Timing results:");
     println!("\tLoading ->   {}", pretty_time(load_time));
     println!("\tRendering -> {}", pretty_time(render_time));
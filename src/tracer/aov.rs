@@ -0,0 +1,191 @@
+use std::str::FromStr;
+
+use super::*;
+use crate::geometry;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Arbitrary output variables: properties of the first surface seen through each pixel, rendered along with the
+/// image for denoising and compositing. Pixels where nothing was hit are zero.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    Albedo,
+    /// Shading normal, in world space
+    Normal,
+    /// Distance from the camera
+    Depth,
+    /// World space position
+    Position,
+    /// Index of the material plus one
+    MaterialId,
+    /// Index of the instance, in the order of the scene file, plus one
+    MeshId,
+}
+
+///
+/// Values of every output variable for one sample, indexed by `Aov as usize`
+pub type AovSample = [Color; AOV_COUNT];
+
+pub const AOV_COUNT: usize = 6;
+
+///
+/// Accumulation buffers for the requested output variables. They are averaged over the samples falling inside
+/// each pixel, except for the ids, which keep the one of the first sample so that they're never blended.
+pub struct AovFilm {
+    pub aovs: Vec<Aov>,
+    pub sums: Vec<Image2D>,
+    pub counts: ndarray::Array2<f32>,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Aov {
+    ///
+    /// Name of the layer or file suffix the variable is written to
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::MaterialId => "material-id",
+            Aov::MeshId => "mesh-id",
+        }
+    }
+
+    ///
+    /// Check if the values are labels, which make no sense averaged
+    pub fn is_id(&self) -> bool {
+        matches!(self, Aov::MaterialId | Aov::MeshId)
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "albedo" => Ok(Aov::Albedo),
+            "normal" => Ok(Aov::Normal),
+            "depth" => Ok(Aov::Depth),
+            "position" => Ok(Aov::Position),
+            "material-id" => Ok(Aov::MaterialId),
+            "mesh-id" => Ok(Aov::MeshId),
+            _ => Err(format!("Unknown output variable: {}", s)),
+        }
+    }
+}
+
+///
+/// Evaluate the requested output variables at the surface hit by a camera ray, all zero if it missed
+pub fn hit_aovs(scene: &Scene, hit: &geometry::MeshIntersection, aovs: &[Aov]) -> AovSample {
+    let mut values = [color::black(); AOV_COUNT];
+    if !hit.distance.is_finite() {
+        return values;
+    }
+    for aov in aovs {
+        values[*aov as usize] = match aov {
+            Aov::Albedo => scene.materials[hit.material as usize].albedo(hit.uv),
+            Aov::Normal => hit.normal,
+            Aov::Depth => Color::repeat(hit.distance),
+            Aov::Position => hit.point.coords,
+            Aov::MaterialId => Color::repeat(hit.material as f32 + 1.0),
+            Aov::MeshId => Color::repeat(hit.instance as f32 + 1.0),
+        };
+    }
+    values
+}
+
+impl AovFilm {
+    pub fn new(width: usize, height: usize, aovs: &[Aov]) -> Self {
+        AovFilm {
+            aovs: aovs.to_vec(),
+            sums: aovs.iter().map(|_| image2d::new(width, height)).collect(),
+            counts: ndarray::Array2::zeros((height, width)),
+        }
+    }
+
+    ///
+    /// Add a sample to the pixel it falls in
+    pub fn add(&mut self, sample: &FilmSample) {
+        let (height, width) = self.counts.dim();
+        let (y, x) = sample.pixel(width, height);
+        let first = self.counts[(y, x)] == 0.0;
+        for (aov, sum) in self.aovs.iter().zip(self.sums.iter_mut()) {
+            if !aov.is_id() {
+                sum[(y, x)] += sample.aovs[*aov as usize];
+            } else if first {
+                sum[(y, x)] = sample.aovs[*aov as usize];
+            }
+        }
+        self.counts[(y, x)] += 1.0;
+    }
+
+    ///
+    /// Add the samples of a pass, unless no variable was requested
+    pub fn add_all(&mut self, samples: &ndarray::Array2<Option<FilmSample>>) {
+        if self.aovs.is_empty() {
+            return;
+        }
+        for sample in samples.iter().flatten() {
+            self.add(sample);
+        }
+    }
+
+    ///
+    /// Final images, along with the names of their variables
+    pub fn images(&self) -> Vec<(&'static str, Image2D)> {
+        self.aovs
+            .iter()
+            .zip(self.sums.iter())
+            .map(|(aov, sum)| {
+                let mut image = sum.clone();
+                if !aov.is_id() {
+                    image.zip_mut_with(&self.counts, |a, &n| {
+                        *a = if n != 0.0 { *a / n } else { color::black() };
+                    });
+                }
+                (aov.name(), image)
+            })
+            .collect()
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn film_sample(x: f32, y: f32, value: f32) -> FilmSample {
+        FilmSample {
+            position: nalgebra::Point2::new(x, y),
+            color: color::black(),
+            aovs: [Color::repeat(value); AOV_COUNT],
+        }
+    }
+
+    ///
+    /// Variables are averaged inside each pixel, but ids are never blended
+    #[test]
+    fn ids_are_not_averaged() {
+        let mut film = AovFilm::new(2, 1, &[Aov::Depth, Aov::MeshId]);
+        film.add(&film_sample(0.2, 0.5, 1.0));
+        film.add(&film_sample(0.7, 0.5, 2.0));
+        film.add(&film_sample(1.5, 0.5, 5.0));
+
+        let images = film.images();
+        assert!(images[0].0 == "depth" && images[1].0 == "mesh-id");
+        assert!(images[0].1[(0, 0)] == Color::repeat(1.5));
+        assert!(images[0].1[(0, 1)] == Color::repeat(5.0));
+        assert!(images[1].1[(0, 0)] == Color::repeat(1.0));
+        assert!(images[1].1[(0, 1)] == Color::repeat(5.0));
+    }
+}
@@ -0,0 +1,104 @@
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// When to stop adding passes to a render. A zero time limit or target error means there's none.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderBudget {
+    pub max_samples: usize,
+    /// Seconds
+    pub time_limit: f32,
+    /// Mean relative error of the pixels
+    pub target_error: f32,
+}
+
+///
+/// Why a render stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    SampleCount,
+    TimeLimit,
+    TargetError,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl RenderBudget {
+    ///
+    /// Decide whether to stop after `samples_done` samples, `passes` of them taken in `elapsed` seconds by this
+    /// run. The time limit stops before a pass that would overrun it, judging by the average time of the passes.
+    pub fn stop(&self, samples_done: usize, passes: usize, elapsed: f32, error: f32) -> Option<StopReason> {
+        if samples_done >= self.max_samples {
+            return Some(StopReason::SampleCount);
+        }
+        if self.time_limit > 0.0 && passes > 0 && elapsed + elapsed / passes as f32 > self.time_limit {
+            return Some(StopReason::TimeLimit);
+        }
+        if self.target_error > 0.0 && error <= self.target_error {
+            return Some(StopReason::TargetError);
+        }
+        None
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Run passes of a fixed duration, with an error shrinking as one over the square root of the samples,
+    /// until the budget says to stop. Returns the number of samples and why it stopped.
+    fn simulate(budget: &RenderBudget, first_sample: usize, pass_time: f32) -> (usize, StopReason) {
+        let mut done = first_sample;
+        loop {
+            done += 1;
+            let passes = done - first_sample;
+            let error = 1.0 / (done as f32).sqrt();
+            if let Some(reason) = budget.stop(done, passes, passes as f32 * pass_time, error) {
+                return (done, reason);
+            }
+        }
+    }
+
+    #[test]
+    fn budget_stops_early() {
+        let samples = RenderBudget {
+            max_samples: 10,
+            time_limit: 0.0,
+            target_error: 0.0,
+        };
+        assert!(simulate(&samples, 0, 1.0) == (10, StopReason::SampleCount));
+        assert!(simulate(&samples, 4, 1.0) == (10, StopReason::SampleCount));
+
+        // Passes of 1.5 seconds: the 6th would end at 9 seconds, past the limit, so it stops after the 5th
+        let time = RenderBudget {
+            max_samples: usize::MAX,
+            time_limit: 8.0,
+            target_error: 0.0,
+        };
+        assert!(simulate(&time, 0, 1.5) == (5, StopReason::TimeLimit));
+        assert!(simulate(&time, 20, 1.5) == (25, StopReason::TimeLimit));
+
+        // The error goes under 0.1 at the 100th sample
+        let error = RenderBudget {
+            max_samples: usize::MAX,
+            time_limit: 0.0,
+            target_error: 0.1,
+        };
+        assert!(simulate(&error, 0, 1.0) == (100, StopReason::TargetError));
+
+        // Whichever comes first
+        let both = RenderBudget {
+            max_samples: 50,
+            ..error
+        };
+        assert!(simulate(&both, 0, 1.0) == (50, StopReason::SampleCount));
+    }
+}
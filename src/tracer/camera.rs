@@ -1,11 +1,11 @@
 use std;
 use std::f32;
-use std::fs::File;
+use std::io::{Error, ErrorKind};
 use std::path::Path;
 
-use rand;
 use ron;
 
+use super::*;
 use crate::geometry;
 
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -13,12 +13,73 @@ use crate::geometry;
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 
 ///
-/// Camera definition
+/// Camera definition. The camera is placed either by looking from `eye` towards `target`, or with the legacy
+/// `position` and `orientation`. The legacy form always looks along +Y with +Z up: its orientation has never been
+/// applied, and files written for it don't hold a meaningful one, so it's still read but ignored.
+/// The zoom is either the unitless `focal` or a field of view. A zero aperture makes it a pinhole camera,
+/// with everything in focus.
 #[derive(Clone, Serialize, Deserialize)]
 struct CameraDef {
-    pub position: nalgebra::Point3<f32>,
-    pub orientation: nalgebra::UnitQuaternion<f32>,
-    pub focal: f32,
+    #[serde(default)]
+    pub position: Option<nalgebra::Point3<f32>>,
+    #[serde(default)]
+    pub orientation: Option<nalgebra::UnitQuaternion<f32>>,
+    #[serde(default)]
+    pub eye: Option<nalgebra::Point3<f32>>,
+    #[serde(default)]
+    pub target: Option<nalgebra::Point3<f32>>,
+    #[serde(default = "default_up")]
+    pub up: nalgebra::Vector3<f32>,
+    #[serde(default)]
+    pub focal: Option<f32>,
+    #[serde(default)]
+    pub fov: Option<FieldOfView>,
+    #[serde(default)]
+    pub aperture: f32,
+    #[serde(default = "default_focus_distance")]
+    pub focus_distance: f32,
+    #[serde(default)]
+    pub autofocus: bool,
+    #[serde(default)]
+    pub bokeh: Bokeh,
+    #[serde(default)]
+    pub projection: Projection,
+}
+
+///
+/// Zoom of a perspective camera
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FieldOfView {
+    /// Angle covered by the height of the image, in degrees
+    Vertical(f32),
+    /// Angle covered by the width of the image, in degrees
+    Horizontal(f32),
+    /// Physical camera, with the sensor width and the focal length in millimeters. The sensor width matches the image width.
+    Sensor { width: f32, focal_length: f32 },
+}
+
+///
+/// How film points are mapped to rays. Only the perspective projection has a lens, the others are always in focus.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    /// Parallel rays, with the smaller side of the image covering `size` scene units
+    Orthographic { size: f32 },
+    /// Equidistant fisheye, whose image circle fits the smaller side of the image and spans `fov` degrees
+    Fisheye { fov: f32 },
+    /// Full 360 by 180 degrees panorama, with the viewing direction at the center of the image
+    Equirectangular,
+}
+
+///
+/// Shape of the lens aperture, which is also the shape out-of-focus highlights take
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Bokeh {
+    #[default]
+    Disk,
+    /// Regular polygon made by the diaphragm blades, rotated by some degrees
+    Polygon { blades: u32, rotation: f32 },
 }
 
 ///
@@ -30,6 +91,13 @@ pub struct Camera {
     pub height: usize,
     pub focal: f32,
     pub aspect: f32,
+    /// Radius of the lens, in scene units
+    pub aperture: f32,
+    /// Distance to the plane in focus, along the viewing direction
+    pub focus_distance: f32,
+    pub autofocus: bool,
+    pub bokeh: Bokeh,
+    pub projection: Projection,
 }
 
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -44,36 +112,313 @@ impl Camera {
         width: usize,
         height: usize,
     ) -> Result<Camera, std::io::Error> {
-        // Load the camera description from the JSON file
-        let file = File::open(filename)?;
-        let json: CameraDef = ron::de::from_reader(file).unwrap();
+        // Load the camera description from the JSON file. The optional fields don't need to be wrapped in Some(...).
+        let text = std::fs::read_to_string(filename)?;
+        let json: CameraDef = ron::de::from_str(&format!("#![enable(implicit_some)]\n{}", text)).unwrap();
 
         // Build the camera
+        let (position, orientation) = match (json.position, json.orientation, json.eye, json.target) {
+            (Some(position), _, None, None) => (position, nalgebra::UnitQuaternion::identity()),
+            (None, None, Some(eye), Some(target)) => (eye, look_at(eye, target, json.up)?),
+            _ => return Err(invalid_camera("either position, or eye and target, must be given")),
+        };
+        let focal = match (json.focal, json.fov) {
+            (Some(focal), None) => focal,
+            (None, Some(fov)) => fov.focal(width, height),
+            (None, None) => return Err(invalid_camera("either focal or fov must be given")),
+            (Some(_), Some(_)) => return Err(invalid_camera("focal and fov can't be given together")),
+        };
         Ok(Camera {
-            position: json.position,
-            orientation: json.orientation,
+            position,
+            orientation,
             width: width,
             height: height,
-            focal: json.focal,
+            focal,
             aspect: (width as f32) / (height as f32),
+            aperture: json.aperture,
+            focus_distance: json.focus_distance,
+            autofocus: json.autofocus,
+            bokeh: json.bokeh,
+            projection: json.projection,
         })
     }
 
     ///
-    /// Trace rays from the camera
-    pub fn make_rays(&self) -> ndarray::Array2<geometry::Ray> {
-        let xbias = rand::random::<f32>() - (self.width as f32) / 2.0;
-        let ybias = rand::random::<f32>() - (self.height as f32) / 2.0;
-        let foobar = 1.0 / f32::min(self.width as f32, self.height as f32);
-
-        ndarray::Array2::from_shape_fn([self.height, self.width], |(y, x)| {
-            let xr = foobar * (x as f32 + xbias);
-            let yr = -foobar * (y as f32 + ybias);
-            let direction = nalgebra::Vector3::new(xr, self.focal, yr).normalize();
-            geometry::Ray {
-                origin: self.position,
-                direction: direction,
+    /// Focus on whatever is seen through the center of the image, if the camera asks for it
+    pub fn focus(&mut self, scene: &Scene) {
+        if !self.autofocus {
+            return;
+        }
+
+        // Every projection looks straight ahead through the center of the image
+        let hit = scene.intersect(geometry::Ray {
+            origin: self.position,
+            direction: self.orientation * nalgebra::Vector3::y(),
+        });
+        if hit.distance.is_finite() {
+            self.focus_distance = hit.distance;
+        }
+    }
+
+    ///
+    /// Ray through a point of the film, in pixel units from the top left corner of the image, and leaving
+    /// from the point of the lens chosen by `u_lens`. Points outside the image circle of a fisheye have no ray.
+    pub fn make_ray(&self, film: nalgebra::Point2<f32>, u_lens: nalgebra::Vector2<f32>) -> Option<geometry::Ray> {
+        let screen = self.film_to_screen(film);
+        let direction = match self.projection {
+            Projection::Perspective => self.pinhole_direction(film),
+            Projection::Orthographic { size } => {
+                let offset = nalgebra::Vector3::new(screen.x, 0.0, screen.y) * size;
+                return Some(self.to_world(offset, nalgebra::Vector3::y()));
             }
-        })
+            Projection::Fisheye { fov } => {
+                // The angle from the viewing direction grows linearly with the distance to the center,
+                // reaching half the field of view at the edge of the smaller side
+                let theta = screen.norm() * fov.to_radians();
+                if theta > f32::min(fov.to_radians() / 2.0, f32::consts::PI) {
+                    return None;
+                }
+                let phi = screen.y.atan2(screen.x);
+                nalgebra::Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+            }
+            Projection::Equirectangular => {
+                let longitude = 2.0 * f32::consts::PI * (film.x / self.width as f32 - 0.5);
+                let latitude = f32::consts::PI * (0.5 - film.y / self.height as f32);
+                nalgebra::Vector3::new(
+                    latitude.cos() * longitude.sin(),
+                    latitude.cos() * longitude.cos(),
+                    latitude.sin(),
+                )
+            }
+        };
+        if self.aperture <= 0.0 || self.projection != Projection::Perspective {
+            return Some(self.to_world(nalgebra::Vector3::zeros(), direction));
+        }
+
+        // Every ray through the same film point converges on the plane in focus
+        let focus = direction * (self.focus_distance / direction.y);
+        let lens = self.bokeh.sample(u_lens) * self.aperture;
+        let lens = nalgebra::Vector3::new(lens.x, 0.0, lens.y);
+        Some(self.to_world(lens, (focus - lens).normalize()))
+    }
+}
+
+impl FieldOfView {
+    ///
+    /// Equivalent unitless focal, which is the distance to the film when the smaller side of the image is one unit long
+    pub fn focal(&self, width: usize, height: usize) -> f32 {
+        let smaller = usize::min(width, height) as f32;
+        let (side, fov) = match self {
+            FieldOfView::Vertical(fov) => (height as f32 / smaller, fov.to_radians()),
+            FieldOfView::Horizontal(fov) => (width as f32 / smaller, fov.to_radians()),
+            FieldOfView::Sensor { width: sensor, focal_length } => {
+                (width as f32 / smaller, 2.0 * (sensor / (2.0 * focal_length)).atan())
+            }
+        };
+        0.5 * side / (fov / 2.0).tan()
+    }
+}
+
+impl Bokeh {
+    ///
+    /// Uniformly distributed point on the unit aperture
+    pub fn sample(&self, u: nalgebra::Vector2<f32>) -> nalgebra::Vector2<f32> {
+        match self {
+            Bokeh::Disk => sample_concentric_disk(u),
+            Bokeh::Polygon { blades, rotation } => {
+                // Pick one of the triangles fanning out from the center, and then a point inside it
+                let blades = u32::max(3, *blades);
+                let scaled = u.x * blades as f32;
+                let blade = f32::min(scaled.floor(), (blades - 1) as f32);
+                let u1 = scaled - blade;
+
+                let angle = |i: f32| rotation.to_radians() + 2.0 * f32::consts::PI * i / blades as f32;
+                let (a0, a1) = (angle(blade), angle(blade + 1.0));
+                let r = u1.sqrt();
+                let (b0, b1) = (r * (1.0 - u.y), r * u.y);
+                nalgebra::Vector2::new(b0 * a0.cos() + b1 * a1.cos(), b0 * a0.sin() + b1 * a1.sin())
+            }
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Camera {
+    ///
+    /// Ray from camera space, where the camera looks along +Y with +Z up, to the scene
+    fn to_world(&self, origin: nalgebra::Vector3<f32>, direction: nalgebra::Vector3<f32>) -> geometry::Ray {
+        geometry::Ray {
+            origin: self.position + self.orientation * origin,
+            direction: self.orientation * direction,
+        }
+    }
+
+    ///
+    /// Film point relative to the center of the image, upwards and in units of the smaller side of the image
+    fn film_to_screen(&self, film: nalgebra::Point2<f32>) -> nalgebra::Vector2<f32> {
+        let pixel_size = 1.0 / f32::min(self.width as f32, self.height as f32);
+        nalgebra::Vector2::new(
+            pixel_size * (film.x - (self.width as f32) / 2.0),
+            -pixel_size * (film.y - (self.height as f32) / 2.0),
+        )
+    }
+
+    ///
+    /// Direction of the perspective ray through the center of the lens
+    fn pinhole_direction(&self, film: nalgebra::Point2<f32>) -> nalgebra::Vector3<f32> {
+        let screen = self.film_to_screen(film);
+        nalgebra::Vector3::new(screen.x, self.focal, screen.y).normalize()
+    }
+}
+
+///
+/// Shirley-Chiu concentric mapping from the unit square to the unit disk, which keeps strata compact
+fn sample_concentric_disk(u: nalgebra::Vector2<f32>) -> nalgebra::Vector2<f32> {
+    let x = 2.0 * u.x - 1.0;
+    let y = 2.0 * u.y - 1.0;
+    if x == 0.0 && y == 0.0 {
+        return nalgebra::Vector2::zeros();
+    }
+
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, f32::consts::FRAC_PI_4 * (y / x))
+    } else {
+        (y, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (x / y))
+    };
+    nalgebra::Vector2::new(r * theta.cos(), r * theta.sin())
+}
+
+///
+/// Rotation that makes the camera look from `eye` towards `target`, with `up` pointing upwards in the image
+fn look_at(
+    eye: nalgebra::Point3<f32>,
+    target: nalgebra::Point3<f32>,
+    up: nalgebra::Vector3<f32>,
+) -> Result<nalgebra::UnitQuaternion<f32>, Error> {
+    let forward = (target - eye).try_normalize(1e-6).ok_or_else(|| invalid_camera("eye and target are the same point"))?;
+    let right = forward.cross(&up).try_normalize(1e-6).ok_or_else(|| invalid_camera("up is parallel to the view"))?;
+    let up = right.cross(&forward);
+    let rotation = nalgebra::Rotation3::from_matrix_unchecked(nalgebra::Matrix3::from_columns(&[right, forward, up]));
+    Ok(nalgebra::UnitQuaternion::from_rotation_matrix(&rotation))
+}
+
+fn invalid_camera(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid camera: {}", message))
+}
+
+fn default_up() -> nalgebra::Vector3<f32> {
+    nalgebra::Vector3::z()
+}
+
+fn default_focus_distance() -> f32 {
+    1.0
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thin_lens(bokeh: Bokeh) -> Camera {
+        Camera {
+            position: nalgebra::Point3::new(1.0, 2.0, 3.0),
+            orientation: nalgebra::UnitQuaternion::identity(),
+            width: 64,
+            height: 32,
+            focal: 0.8,
+            aspect: 2.0,
+            aperture: 0.1,
+            focus_distance: 5.0,
+            autofocus: false,
+            bokeh,
+            projection: Projection::Perspective,
+        }
+    }
+
+    ///
+    /// Whatever the point of the lens, the rays through a film point meet on the plane in focus
+    #[test]
+    fn focus_plane_is_sharp() {
+        let bokehs = [Bokeh::Disk, Bokeh::Polygon { blades: 6, rotation: 15.0 }];
+        for bokeh in bokehs.iter() {
+            let camera = thin_lens(*bokeh);
+            let film = nalgebra::Point2::new(10.3, 25.7);
+            let pinhole = camera.pinhole_direction(film);
+            let expected = camera.position + pinhole * (camera.focus_distance / pinhole.y);
+            for i in 0..16 {
+                let u = nalgebra::Vector2::new((i % 4) as f32 / 4.0 + 0.1, (i / 4) as f32 / 4.0 + 0.1);
+                let ray = camera.make_ray(film, u).unwrap();
+                let t = (expected.y - ray.origin.y) / ray.direction.y;
+                let hit = ray.origin + ray.direction * t;
+                assert!((hit - expected).norm() < 1e-4, "{} != {}", hit, expected);
+                assert!((ray.origin - camera.position).norm() <= camera.aperture * 1.0001);
+            }
+        }
+    }
+
+    ///
+    /// Directions at the center and the edges of the image, for the projections with a wide field of view
+    #[test]
+    fn wide_projections() {
+        let direction = |camera: &Camera, x: f32, y: f32| {
+            camera.make_ray(nalgebra::Point2::new(x, y), nalgebra::Vector2::zeros()).map(|ray| ray.direction)
+        };
+        let close = |a: nalgebra::Vector3<f32>, b: nalgebra::Vector3<f32>| (a - b).norm() < 1e-5;
+
+        let mut camera = thin_lens(Bokeh::Disk);
+        camera.projection = Projection::Fisheye { fov: 180.0 };
+        assert!(close(direction(&camera, 32.0, 16.0).unwrap(), nalgebra::Vector3::y()));
+        assert!(close(direction(&camera, 32.0, 0.0).unwrap(), nalgebra::Vector3::z()));
+        assert!(close(direction(&camera, 16.0, 16.0).unwrap(), -nalgebra::Vector3::x()));
+        assert!(direction(&camera, 0.0, 0.0).is_none());
+
+        camera.projection = Projection::Equirectangular;
+        assert!(close(direction(&camera, 32.0, 16.0).unwrap(), nalgebra::Vector3::y()));
+        assert!(close(direction(&camera, 48.0, 16.0).unwrap(), nalgebra::Vector3::x()));
+        assert!(close(direction(&camera, 0.0, 16.0).unwrap(), -nalgebra::Vector3::y()));
+        assert!(close(direction(&camera, 10.0, 0.0).unwrap(), nalgebra::Vector3::z()));
+    }
+
+    ///
+    /// The look-at rotation and the fields of view must agree with the raw quaternion and focal
+    #[test]
+    fn look_at_and_field_of_view() {
+        let eye = nalgebra::Point3::new(1.0, 2.0, 3.0);
+        let straight = look_at(eye, eye + nalgebra::Vector3::y(), nalgebra::Vector3::z()).unwrap();
+        assert!(straight.angle_to(&nalgebra::UnitQuaternion::identity()) < 1e-5);
+
+        let sideways = look_at(eye, nalgebra::Point3::new(5.0, 2.0, 3.0), nalgebra::Vector3::z()).unwrap();
+        assert!((sideways * nalgebra::Vector3::y() - nalgebra::Vector3::x()).norm() < 1e-5);
+        assert!((sideways * nalgebra::Vector3::z() - nalgebra::Vector3::z()).norm() < 1e-5);
+        assert!(look_at(eye, eye + nalgebra::Vector3::z(), nalgebra::Vector3::z()).is_err());
+
+        assert!((FieldOfView::Vertical(90.0).focal(64, 64) - 0.5).abs() < 1e-5);
+        assert!((FieldOfView::Vertical(90.0).focal(64, 32) - 0.5).abs() < 1e-5);
+        assert!((FieldOfView::Horizontal(90.0).focal(64, 32) - 1.0).abs() < 1e-5);
+        let sensor = FieldOfView::Sensor {
+            width: 36.0,
+            focal_length: 18.0,
+        };
+        assert!((sensor.focal(64, 64) - 0.5).abs() < 1e-5);
+    }
+
+    ///
+    /// Legacy cameras must keep looking along +Y whatever their orientation, as they always did
+    #[test]
+    fn legacy_orientation_is_ignored() {
+        let filename = std::env::temp_dir().join(format!("rspt-camera-{}.ron", std::process::id()));
+        std::fs::write(&filename, "CameraDef(position: [0.0, -3.0, 1.7], orientation: [1.0, 0.0, 0.0, 0.0], focal: 0.5)").unwrap();
+        let camera = Camera::from_json(&filename, 64, 64).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+
+        let ray = camera.make_ray(nalgebra::Point2::new(32.0, 32.0), nalgebra::Vector2::new(0.5, 0.5)).unwrap();
+        assert!((ray.direction - nalgebra::Vector3::y()).norm() < 1e-5);
+        assert!((ray.origin - nalgebra::Point3::new(0.0, -3.0, 1.7)).norm() < 1e-5);
     }
 }
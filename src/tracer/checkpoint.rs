@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// State of an interrupted render. The samplers have no state of their own, every sample stream being derived
+/// from the seed, the pixel and the sample index, so the seed and the number of samples done are all it takes
+/// to continue with the same random numbers. The fingerprint identifies the scene, camera and options the
+/// buffers were rendered with.
+pub struct Checkpoint {
+    pub fingerprint: u64,
+    pub seed: u64,
+    pub samples_done: usize,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Checkpoint {
+    ///
+    /// Write the checkpoint along with the raw accumulation buffers, through a temporary file so that an
+    /// interruption while writing doesn't destroy the previous checkpoint
+    pub fn save(&self, filename: &Path, film: &Film, aov_film: &AovFilm, stats: &PixelStats) -> Result<(), Error> {
+        let mut temp_name = filename.as_os_str().to_owned();
+        temp_name.push(".tmp");
+        let temp = std::path::PathBuf::from(temp_name);
+
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        writer.write_all(MAGIC)?;
+        for value in [self.fingerprint, self.seed, self.samples_done as u64].iter() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        let (height, width) = film.weights.dim();
+        for value in [width as u64, height as u64, aov_film.sums.len() as u64].iter() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+
+        let images = std::iter::once(&film.sum).chain(aov_film.sums.iter());
+        for value in images.flat_map(|image| image.iter().flat_map(|c| c.iter())) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        let buffers = [&film.weights, &aov_film.counts, &stats.count, &stats.mean, &stats.m2];
+        for value in buffers.iter().flat_map(|buffer| buffer.iter()) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        std::fs::rename(&temp, filename)
+    }
+
+    ///
+    /// Read a checkpoint into the accumulation buffers, which must have the same size and variables as the ones
+    /// it was saved from. The checkpoint must have been made with the given fingerprint.
+    pub fn load(
+        filename: &Path,
+        fingerprint: u64,
+        film: &mut Film,
+        aov_film: &mut AovFilm,
+        stats: &mut PixelStats,
+    ) -> Result<Self, Error> {
+        let mut reader = BufReader::new(File::open(filename)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_checkpoint("not a checkpoint file"));
+        }
+        let checkpoint = Checkpoint {
+            fingerprint: read_u64(&mut reader)?,
+            seed: read_u64(&mut reader)?,
+            samples_done: read_u64(&mut reader)? as usize,
+        };
+        if checkpoint.fingerprint != fingerprint {
+            return Err(invalid_checkpoint("it was made with a different scene, camera or options"));
+        }
+        let (height, width) = film.weights.dim();
+        let shape = (read_u64(&mut reader)?, read_u64(&mut reader)?, read_u64(&mut reader)?);
+        if shape != (width as u64, height as u64, aov_film.sums.len() as u64) {
+            return Err(invalid_checkpoint("the image size or the output variables are different"));
+        }
+
+        let images = std::iter::once(&mut film.sum).chain(aov_film.sums.iter_mut());
+        for value in images.flat_map(|image| image.iter_mut().flat_map(|c| c.iter_mut())) {
+            *value = read_f32(&mut reader)?;
+        }
+        let buffers = [
+            &mut film.weights,
+            &mut aov_film.counts,
+            &mut stats.count,
+            &mut stats.mean,
+            &mut stats.m2,
+        ];
+        for buffer in buffers {
+            for value in buffer.iter_mut() {
+                *value = read_f32(&mut reader)?;
+            }
+        }
+        Ok(checkpoint)
+    }
+}
+
+///
+/// 64-bit FNV-1a hash of several byte strings, stable across runs and platforms
+pub fn fingerprint(parts: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for part in parts {
+        // The length separates the parts, so that moving bytes from one to the next changes the hash
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(part.iter()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+const MAGIC: &[u8; 8] = b"RSPTCKP1";
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> Result<f32, Error> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn invalid_checkpoint(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid checkpoint: {}", message))
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// A checkpoint must restore the buffers exactly
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut film = Film::new(4, 3, Filter::Tent);
+        let mut aov_film = AovFilm::new(4, 3, &[Aov::Albedo]);
+        let mut stats = PixelStats::new(4, 3);
+        for i in 0..20 {
+            let sample = FilmSample {
+                position: nalgebra::Point2::new((i as f32 * 0.37) % 4.0, (i as f32 * 0.61) % 3.0),
+                color: Color::new(i as f32, 0.5, 1.0 / (i as f32 + 1.0)),
+                aovs: [Color::repeat(i as f32 * 0.1); AOV_COUNT],
+            };
+            film.splat(&sample);
+            aov_film.add(&sample);
+            stats.add(&sample);
+        }
+        let checkpoint = Checkpoint {
+            fingerprint: fingerprint(&[b"scene", b"options"]),
+            seed: 42,
+            samples_done: 20,
+        };
+        let filename = std::env::temp_dir().join(format!("rspt-checkpoint-{}", std::process::id()));
+        checkpoint.save(&filename, &film, &aov_film, &stats).unwrap();
+
+        let mut restored_film = Film::new(4, 3, Filter::Tent);
+        let mut restored_aovs = AovFilm::new(4, 3, &[Aov::Albedo]);
+        let mut restored_stats = PixelStats::new(4, 3);
+        let restored =
+            Checkpoint::load(&filename, checkpoint.fingerprint, &mut restored_film, &mut restored_aovs, &mut restored_stats)
+                .unwrap();
+        assert!(restored.fingerprint == checkpoint.fingerprint);
+        assert!(restored.seed == 42 && restored.samples_done == 20);
+        assert!(restored_film.sum == film.sum && restored_film.weights == film.weights);
+        assert!(restored_aovs.sums == aov_film.sums && restored_aovs.counts == aov_film.counts);
+        assert!(restored_stats.mean == stats.mean && restored_stats.m2 == stats.m2);
+
+        let mut wrong_size = Film::new(3, 4, Filter::Tent);
+        assert!(Checkpoint::load(&filename, checkpoint.fingerprint, &mut wrong_size, &mut restored_aovs, &mut restored_stats).is_err());
+        assert!(Checkpoint::load(&filename, 0, &mut restored_film, &mut restored_aovs, &mut restored_stats).is_err());
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
@@ -18,3 +18,9 @@ pub struct Color {
 pub fn black() -> Color {
     nalgebra::zero()
 }
+
+///
+/// Relative luminance of a linear Rec. 709 color
+pub fn luminance(color: &Color) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
@@ -0,0 +1,150 @@
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Piecewise-constant distribution over [0, 1), with one bucket per weight
+pub struct Distribution1D {
+    weights: Vec<f32>,
+    cdf: Vec<f32>,
+    total: f32,
+}
+
+///
+/// Piecewise-constant distribution over [0, 1)^2: a row is picked from the marginal distribution,
+/// and then a column from the distribution of that row
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Distribution1D {
+    pub fn new(weights: Vec<f32>) -> Self {
+        let mut cdf = Vec::with_capacity(weights.len());
+        let mut total = 0.0;
+        for w in &weights {
+            total += w;
+            cdf.push(total);
+        }
+        Distribution1D {
+            weights,
+            cdf,
+            total,
+        }
+    }
+
+    ///
+    /// Sum of all the weights; nothing can be sampled if it's zero
+    pub fn total(&self) -> f32 {
+        self.total
+    }
+
+    ///
+    /// Pick a bucket with probability proportional to its weight, and a uniformly distributed point inside it.
+    /// Returns the point and the index of its bucket.
+    pub fn sample(&self, u: f32) -> (f32, usize) {
+        let target = u * self.total;
+        let index = usize::min(self.cdf.partition_point(|&a| a <= target), self.cdf.len() - 1);
+        let start = if index > 0 { self.cdf[index - 1] } else { 0.0 };
+        let offset = if self.weights[index] > 0.0 {
+            ((target - start) / self.weights[index]).clamp(0.0, 1.0)
+        } else {
+            0.5
+        };
+        ((index as f32 + offset) / self.cdf.len() as f32, index)
+    }
+
+    ///
+    /// Probability density of a bucket, with respect to the [0, 1) interval
+    pub fn pdf(&self, index: usize) -> f32 {
+        if self.total > 0.0 {
+            self.weights[index] * self.cdf.len() as f32 / self.total
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Distribution2D {
+    ///
+    /// Build the distribution from a row-major grid of weights
+    pub fn new(weights: &[f32], width: usize, height: usize) -> Self {
+        let rows: Vec<Distribution1D> = weights.chunks(width).map(|r| Distribution1D::new(r.to_vec())).collect();
+        assert!(rows.len() == height);
+        let marginal = Distribution1D::new(rows.iter().map(Distribution1D::total).collect());
+        Distribution2D { rows, marginal }
+    }
+
+    pub fn total(&self) -> f32 {
+        self.marginal.total()
+    }
+
+    ///
+    /// Sample a point, returned as (x, y) along with its density
+    pub fn sample(&self, u1: f32, u2: f32) -> (nalgebra::Vector2<f32>, f32) {
+        let (y, row) = self.marginal.sample(u1);
+        let (x, column) = self.rows[row].sample(u2);
+        let pdf = self.marginal.pdf(row) * self.rows[row].pdf(column);
+        (nalgebra::Vector2::new(x, y), pdf)
+    }
+
+    ///
+    /// Probability density of a point, with respect to the unit square
+    pub fn pdf(&self, point: nalgebra::Vector2<f32>) -> f32 {
+        let height = self.rows.len();
+        let width = self.rows[0].cdf.len();
+        let row = usize::min((point.y * height as f32) as usize, height - 1);
+        let column = usize::min((point.x * width as f32) as usize, width - 1);
+        self.marginal.pdf(row) * self.rows[row].pdf(column)
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// The density of a sample must match the one pdf() gives, empty rows and columns must never be chosen,
+    /// and the density must integrate to one over the unit square
+    #[test]
+    fn distribution_2d_is_consistent() {
+        #[rustfmt::skip]
+        let weights = [
+            0.0, 0.0, 0.0,
+            1.0, 0.0, 3.0,
+            0.0, 0.0, 0.0,
+            0.5, 2.0, 0.5,
+        ];
+        let distribution = Distribution2D::new(&weights, 3, 4);
+        assert!(distribution.total() == 7.0);
+
+        let steps = 64;
+        for i in 0..steps {
+            for j in 0..steps {
+                let (u1, u2) = (i as f32 / steps as f32, j as f32 / steps as f32);
+                let (point, pdf) = distribution.sample(u1, u2);
+                assert!(point.x >= 0.0 && point.x <= 1.0 && point.y >= 0.0 && point.y <= 1.0);
+                let (row, column) = (usize::min((point.y * 4.0) as usize, 3), usize::min((point.x * 3.0) as usize, 2));
+                assert!(weights[3 * row + column] > 0.0, "picked the empty cell ({}, {})", row, column);
+                assert!(pdf > 0.0 && (pdf - distribution.pdf(point)).abs() < 1e-5 * pdf);
+            }
+        }
+
+        let mut integral = 0.0;
+        for row in 0..4 {
+            for column in 0..3 {
+                let center = nalgebra::Vector2::new((column as f32 + 0.5) / 3.0, (row as f32 + 0.5) / 4.0);
+                integral += distribution.pdf(center) / 12.0;
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-5);
+    }
+}
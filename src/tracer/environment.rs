@@ -0,0 +1,197 @@
+use std::f32;
+use std::path::Path;
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Light arriving from infinitely far away, as declared in the scene file
+#[derive(Serialize, Deserialize)]
+pub enum EnvironmentDef {
+    Constant(Color),
+    /// Equirectangular .hdr or .exr image, with +Z at the top row. The rotation is in degrees around +Z.
+    Map {
+        file: String,
+        #[serde(default)]
+        rotation: f32,
+        #[serde(default = "unit_intensity")]
+        intensity: f32,
+    },
+}
+
+///
+/// Environment light, with the distribution used to sample it
+pub struct Environment {
+    radiance: Radiance,
+    rotation: f32,
+    intensity: f32,
+}
+
+///
+/// A direction sampled towards the environment
+pub struct EnvironmentSample {
+    pub direction: nalgebra::Vector3<f32>,
+    pub radiance: Color,
+    pub pdf: f32,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Environment {
+    ///
+    /// Nothing around the scene
+    pub fn none() -> Self {
+        Environment {
+            radiance: Radiance::Constant(color::black()),
+            rotation: 0.0,
+            intensity: 1.0,
+        }
+    }
+
+    ///
+    /// Build the environment, loading the image relative to `base_dir`
+    pub fn new(def: &EnvironmentDef, base_dir: &Path) -> Result<Self, std::io::Error> {
+        match def {
+            EnvironmentDef::Constant(color) => Ok(Environment {
+                radiance: Radiance::Constant(*color),
+                rotation: 0.0,
+                intensity: 1.0,
+            }),
+            EnvironmentDef::Map {
+                file,
+                rotation,
+                intensity,
+            } => {
+                let texture = Texture::load(&base_dir.join(file), WrapMode::Repeat)?;
+
+                // Each texel is weighted by its brightness and the solid angle it covers
+                let mut weights = Vec::with_capacity(texture.texels.len());
+                for y in 0..texture.height {
+                    let sin_theta = (f32::consts::PI * (y as f32 + 0.5) / texture.height as f32).sin();
+                    for x in 0..texture.width {
+                        weights.push(color::luminance(&texture.texels[y * texture.width + x]) * sin_theta);
+                    }
+                }
+                let distribution = Distribution2D::new(&weights, texture.width, texture.height);
+
+                Ok(Environment {
+                    radiance: Radiance::Map(texture, distribution),
+                    rotation: rotation.to_radians(),
+                    intensity: *intensity,
+                })
+            }
+        }
+    }
+
+    ///
+    /// Check if the environment emits any light at all
+    pub fn is_black(&self) -> bool {
+        match &self.radiance {
+            Radiance::Constant(color) => *color == color::black(),
+            Radiance::Map(_, distribution) => distribution.total() <= 0.0 || self.intensity == 0.0,
+        }
+    }
+
+    ///
+    /// Light arriving from a direction
+    pub fn radiance(&self, direction: nalgebra::Vector3<f32>) -> Color {
+        match &self.radiance {
+            Radiance::Constant(color) => *color,
+            Radiance::Map(texture, _) => texture.sample(self.direction_to_uv(direction)) * self.intensity,
+        }
+    }
+
+    ///
+    /// Probability density, with respect to solid angle, of `sample` choosing a direction
+    pub fn pdf(&self, direction: nalgebra::Vector3<f32>) -> f32 {
+        match &self.radiance {
+            Radiance::Constant(_) => 1.0 / (4.0 * f32::consts::PI),
+            Radiance::Map(_, distribution) => {
+                let uv = self.direction_to_uv(direction);
+                let sin_theta = (1.0 - direction.z * direction.z).max(0.0).sqrt();
+                if sin_theta > 0.0 {
+                    distribution.pdf(nalgebra::Vector2::new(uv.x, 1.0 - uv.y))
+                        / (2.0 * f32::consts::PI * f32::consts::PI * sin_theta)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    ///
+    /// Pick a direction, uniformly for a constant environment and proportionally to the brightness of a map
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Option<EnvironmentSample> {
+        let u = sampler.next_2d();
+        if self.is_black() {
+            return None;
+        }
+
+        let (u1, u2) = (u.x, u.y);
+        let direction = match &self.radiance {
+            Radiance::Constant(_) => {
+                let z = 1.0 - 2.0 * u1;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * f32::consts::PI * u2;
+                nalgebra::Vector3::new(r * phi.cos(), r * phi.sin(), z)
+            }
+            Radiance::Map(_, distribution) => {
+                // Image coordinates have the top row at zero
+                let (point, _) = distribution.sample(u1, u2);
+                self.uv_to_direction(nalgebra::Vector2::new(point.x, 1.0 - point.y))
+            }
+        };
+
+        let pdf = self.pdf(direction);
+        if pdf > 0.0 {
+            Some(EnvironmentSample {
+                direction,
+                radiance: self.radiance(direction),
+                pdf,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+enum Radiance {
+    Constant(Color),
+    Map(Texture<Color>, Distribution2D),
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Environment {
+    ///
+    /// Equirectangular mapping, with v = 1 towards +Z
+    fn direction_to_uv(&self, direction: nalgebra::Vector3<f32>) -> nalgebra::Vector2<f32> {
+        let phi = direction.y.atan2(direction.x) - self.rotation;
+        let theta = direction.z.clamp(-1.0, 1.0).acos();
+        nalgebra::Vector2::new(
+            (phi / (2.0 * f32::consts::PI)).rem_euclid(1.0),
+            1.0 - theta / f32::consts::PI,
+        )
+    }
+
+    fn uv_to_direction(&self, uv: nalgebra::Vector2<f32>) -> nalgebra::Vector3<f32> {
+        let phi = 2.0 * f32::consts::PI * uv.x + self.rotation;
+        let theta = f32::consts::PI * (1.0 - uv.y);
+        nalgebra::Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+    }
+}
+
+fn unit_intensity() -> f32 {
+    1.0
+}
@@ -0,0 +1,166 @@
+use std::f32;
+use std::str::FromStr;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Pixel reconstruction filters. Each sample is splatted to every pixel within the radius of the filter,
+/// weighted by the filter evaluated at the offset between the sample and the center of the pixel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3, whose negative lobes sharpen the image slightly
+    Mitchell,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Filter {
+    ///
+    /// Half the width of the filter, in pixels
+    pub fn radius(&self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+        }
+    }
+
+    ///
+    /// Weight of a sample at an offset from the center of the pixel
+    pub fn eval(&self, dx: f32, dy: f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            "gaussian" => Ok(Filter::Gaussian),
+            "mitchell" => Ok(Filter::Mitchell),
+            _ => Err(format!("Unknown pixel filter: {}", s)),
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Filter {
+    ///
+    /// The filters are separable
+    fn eval_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        let radius = self.radius();
+        if x > radius {
+            return 0.0;
+        }
+
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => radius - x,
+            Filter::Gaussian => {
+                // Shifted down so that it reaches zero at the radius
+                let sigma = 0.5;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(radius)
+            }
+            Filter::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let x2 = x * x;
+                let x3 = x2 * x;
+                let value = if x < 1.0 {
+                    (12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)
+                } else {
+                    (-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)
+                };
+                value / 6.0
+            }
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::tracer::{color, Color, Film, FilmSample};
+
+    const FILTERS: [Filter; 4] = [Filter::Box, Filter::Tent, Filter::Gaussian, Filter::Mitchell];
+
+    ///
+    /// Nothing is splatted beyond the radius, and no offset weighs more than the center of the pixel
+    #[test]
+    fn weights_vanish_outside_the_radius() {
+        for filter in FILTERS.iter() {
+            let radius = filter.radius();
+            let center = filter.eval(0.0, 0.0);
+            assert!(center > 0.0, "{:?}", filter);
+            for offset in [radius + 1e-3, radius + 0.5, 10.0].iter() {
+                assert!(filter.eval(*offset, 0.0) == 0.0, "{:?}", filter);
+                assert!(filter.eval(0.0, -*offset) == 0.0, "{:?}", filter);
+                assert!(filter.eval(*offset, *offset) == 0.0, "{:?}", filter);
+            }
+            for i in -20..=20 {
+                for j in -20..=20 {
+                    let (dx, dy) = (i as f32 / 20.0 * radius, j as f32 / 20.0 * radius);
+                    assert!(filter.eval(dx, dy) <= center, "{:?} at ({}, {})", filter, dx, dy);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Splats are divided by the weights they add, so a constant radiance must give a constant image, even at the
+    /// borders where part of the filter falls outside of the film
+    #[test]
+    fn constant_radiance_gives_a_constant_image() {
+        let radiance = Color::new(0.25, 0.5, 2.0);
+        let mut rng = SmallRng::seed_from_u64(3);
+        for filter in FILTERS.iter() {
+            let (width, height) = (8, 6);
+            let mut film = Film::new(width, height, *filter);
+
+            // A single sample spread over its neighbours already shows in each of them with its own value
+            film.splat(&sample(4.3, 2.6, radiance));
+            for (pixel, weight) in film.image().indexed_iter().zip(film.weights.iter()) {
+                if *weight != 0.0 {
+                    assert!((pixel.1 - radiance).norm() < 1e-5, "{:?} at {:?}", filter, pixel.0);
+                }
+            }
+
+            for _ in 0..16 * width * height {
+                film.splat(&sample(rng.gen::<f32>() * width as f32, rng.gen::<f32>() * height as f32, radiance));
+            }
+            for (index, pixel) in film.image().indexed_iter() {
+                assert!((pixel - radiance).norm() < 1e-4, "{:?} at {:?}", filter, index);
+            }
+        }
+    }
+
+    fn sample(x: f32, y: f32, color: Color) -> FilmSample {
+        FilmSample {
+            position: nalgebra::Point2::new(x, y),
+            color,
+            aovs: [color::black(); crate::tracer::AOV_COUNT],
+        }
+    }
+}
@@ -2,11 +2,14 @@
 // 1-dimensional Halton sequence
 // ====================================================================================================================
 
+const MAX_DENOMINATOR: f64 = (1u64 << 32) as f64;
+
 ///
 /// Halton sequence of base N
 pub struct HaltonSeq {
     base: usize,
     offset: usize,
+    scramble: u64,
 }
 
 ///
@@ -18,6 +21,18 @@ impl HaltonSeq {
         HaltonSeq {
             base: base,
             offset: 0,
+            scramble: 0,
+        }
+    }
+
+    ///
+    /// Create a sequence with random digit scrambling: each digit position gets its own offset, modulo the base,
+    /// derived from `scramble`. Different values give decorrelated sequences with the same distribution.
+    pub fn scrambled(base: usize, scramble: u64) -> Self {
+        HaltonSeq {
+            base,
+            offset: 0,
+            scramble,
         }
     }
 
@@ -33,14 +48,31 @@ impl HaltonSeq {
         let mut sample = 0.0;
         let mut denominator = self.base as f64;
         let mut n = self.offset;
-        while n > 0 {
-            let multiplier: usize = n % self.base;
+        let mut position = 0;
+
+        // The zero digits past the most significant one of the index are scrambled too, up to the precision of the result
+        while n > 0 || (self.scramble != 0 && denominator < MAX_DENOMINATOR) {
+            let mut multiplier: usize = n % self.base;
+            if self.scramble != 0 {
+                multiplier = (multiplier + digit_offset(self.scramble, position, self.base)) % self.base;
+            }
             sample += (multiplier as f64) / denominator;
             n = n / self.base;
             denominator *= self.base as f64;
+            position += 1;
         }
         self.offset += 1;
 
         sample
     }
 }
+
+///
+/// Pseudo-random offset of a digit position, in [0, base)
+fn digit_offset(scramble: u64, position: u64, base: usize) -> usize {
+    let mut x = scramble ^ position.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    x ^= x >> 33;
+    (x % base as u64) as usize
+}
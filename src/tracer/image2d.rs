@@ -1,3 +1,6 @@
+use std::io::Error;
+use std::str::FromStr;
+
 use super::*;
 
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -6,6 +9,31 @@ use super::*;
 
 pub type Image2D = ndarray::Array2<Color>;
 
+///
+/// Accumulation buffer for filtered samples, keeping the weighted sum of the samples and the sum of the weights
+pub struct Film {
+    pub sum: Image2D,
+    pub weights: ndarray::Array2<f32>,
+    pub filter: Filter,
+}
+
+///
+/// Running mean and variance of the luminance of the samples taken in each pixel, updated with Welford's
+/// algorithm. Unlike the film, each sample only counts for the pixel it was taken in.
+pub struct PixelStats {
+    pub count: ndarray::Array2<f32>,
+    pub mean: ndarray::Array2<f32>,
+    pub m2: ndarray::Array2<f32>,
+}
+
+///
+/// Floating point format of the channels of an OpenEXR file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrPrecision {
+    Half,
+    Float,
+}
+
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 // Public functions
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -25,28 +53,216 @@ pub fn scale(image: &mut Image2D, factor: usize) -> () {
     image.map_inplace(|a| *a = *a * s);
 }
 
-pub fn save_png(image: &Image2D, filename: &std::path::Path) -> image::ImageResult<()> {
-    // Compute the scale factor
-    let mut high = f32::NEG_INFINITY;
-    //let high = self.pixels.max();
-    for value in image.iter() {
-        high = f32::max(high, value.x);
-        high = f32::max(high, value.y);
-        high = f32::max(high, value.z);
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        Film {
+            sum: new(width, height),
+            weights: ndarray::Array2::zeros((height, width)),
+            filter,
+        }
     }
-    let scale = 1.0 / high;
-    let gamma = 1.0 / 2.2;
 
-    // Rescale the image to the range [0, 255]
+    ///
+    /// Add a sample to every pixel whose center is within the radius of the filter
+    pub fn splat(&mut self, sample: &FilmSample) {
+        self.splat_where(sample, |_, _| true);
+    }
+
+    ///
+    /// Splat a whole pass of samples, only into the pixels that were sampled in it. Pixels that adaptive sampling
+    /// has left alone would otherwise keep taking in the samples of their noisier neighbours, and drift towards them.
+    pub fn splat_all(&mut self, samples: &ndarray::Array2<Option<FilmSample>>, active: &ndarray::Array2<bool>) {
+        for sample in samples.iter().flatten() {
+            self.splat_where(sample, |y, x| active[(y, x)]);
+        }
+    }
+
+    ///
+    /// Normalize the accumulated samples by their weights
+    pub fn image(&self) -> Image2D {
+        let mut image = self.sum.clone();
+        image.zip_mut_with(&self.weights, |a, &w| {
+            *a = if w != 0.0 { *a / w } else { color::black() };
+        });
+        image
+    }
+
+    ///
+    /// Add a sample to the pixels within the radius of the filter that the predicate accepts, given as (y, x)
+    fn splat_where(&mut self, sample: &FilmSample, accept: impl Fn(usize, usize) -> bool) {
+        let radius = self.filter.radius();
+        let (height, width) = self.weights.dim();
+        let x0 = f32::max(0.0, (sample.position.x - 0.5 - radius).ceil()) as usize;
+        let y0 = f32::max(0.0, (sample.position.y - 0.5 - radius).ceil()) as usize;
+        let x1 = usize::min(width, f32::max(0.0, (sample.position.x - 0.5 + radius).floor() + 1.0) as usize);
+        let y1 = usize::min(height, f32::max(0.0, (sample.position.y - 0.5 + radius).floor() + 1.0) as usize);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let dx = sample.position.x - (x as f32 + 0.5);
+                let dy = sample.position.y - (y as f32 + 0.5);
+                let weight = self.filter.eval(dx, dy);
+                if weight != 0.0 && accept(y, x) {
+                    self.sum[(y, x)] += sample.color * weight;
+                    self.weights[(y, x)] += weight;
+                }
+            }
+        }
+    }
+}
+
+impl PixelStats {
+    pub fn new(width: usize, height: usize) -> Self {
+        PixelStats {
+            count: ndarray::Array2::zeros((height, width)),
+            mean: ndarray::Array2::zeros((height, width)),
+            m2: ndarray::Array2::zeros((height, width)),
+        }
+    }
+
+    ///
+    /// Add the luminance of a sample to the pixel it falls in
+    pub fn add(&mut self, sample: &FilmSample) {
+        let (height, width) = self.count.dim();
+        let (y, x) = sample.pixel(width, height);
+        let value = color::luminance(&sample.color);
+
+        self.count[(y, x)] += 1.0;
+        let delta = value - self.mean[(y, x)];
+        self.mean[(y, x)] += delta / self.count[(y, x)];
+        self.m2[(y, x)] += delta * (value - self.mean[(y, x)]);
+    }
+
+    ///
+    /// Add every sample taken in a pass
+    pub fn add_all(&mut self, samples: &ndarray::Array2<Option<FilmSample>>) {
+        for sample in samples.iter().flatten() {
+            self.add(sample);
+        }
+    }
+
+    ///
+    /// Standard error of the mean of a pixel, relative to the mean. It's infinite until there are two samples.
+    /// Very dark pixels are compared to a floor instead, so that they can converge.
+    pub fn relative_error(&self, y: usize, x: usize) -> f32 {
+        let n = self.count[(y, x)];
+        if n < 2.0 {
+            return f32::INFINITY;
+        }
+        let variance = self.m2[(y, x)] / (n - 1.0);
+        (variance / n).sqrt() / f32::max(self.mean[(y, x)], DARK_PIXEL_FLOOR)
+    }
+
+    ///
+    /// Average of the relative errors of every pixel, infinite until each one has two samples
+    pub fn mean_relative_error(&self) -> f32 {
+        let (height, width) = self.count.dim();
+        let mut sum = 0.0;
+        for y in 0..height {
+            for x in 0..width {
+                sum += self.relative_error(y, x);
+            }
+        }
+        sum / (width * height) as f32
+    }
+
+    ///
+    /// Pixels whose relative error is still above the threshold
+    pub fn unconverged(&self, threshold: f32) -> ndarray::Array2<bool> {
+        ndarray::Array2::from_shape_fn(self.count.dim(), |(y, x)| self.relative_error(y, x) > threshold)
+    }
+
+    ///
+    /// Number of samples taken in each pixel, as an image
+    pub fn sample_counts(&self) -> Image2D {
+        self.count.mapv(Color::repeat)
+    }
+}
+
+///
+/// Save the named layers to a file, in the format given by its extension. Only OpenEXR can hold more than one
+/// layer, so the other formats write the first one to `filename` and the rest next to it, with the name of
+/// the layer appended to the file name, e.g. image.albedo.png. The tone mapping is only applied to the first
+/// layer of a PNG, the others are just normalized and stored linearly, except for normals, which are mapped
+/// from [-1, 1] to [0, 1] so that negative components survive.
+pub fn save(
+    layers: &[(&str, &Image2D)],
+    filename: &std::path::Path,
+    precision: ExrPrecision,
+    tone_mapping: &ToneMapping,
+) -> Result<(), Error> {
+    let extension = extension(filename);
+    if extension == "exr" {
+        return save_exr(layers, filename, precision);
+    }
+
+    for (i, ((name, image), layer_file)) in layers.iter().zip(layer_files(layers, filename)).enumerate() {
+        match extension.as_str() {
+            "hdr" => save_hdr(image, &layer_file).map_err(Error::other)?,
+            _ if i == 0 => save_png(image, &layer_file, tone_mapping).map_err(Error::other)?,
+            _ if *name == Aov::Normal.name() => {
+                let encoded = image.mapv(|n| n * 0.5 + Color::repeat(0.5));
+                save_png(&encoded, &layer_file, &ToneMapping::linear()).map_err(Error::other)?
+            }
+            _ => save_png(image, &layer_file, &ToneMapping::normalized(image)).map_err(Error::other)?,
+        }
+    }
+    Ok(())
+}
+
+///
+/// Same as `save`, but every file is first written under a temporary name and then renamed, so that programs
+/// watching it never see it half written
+pub fn save_atomically(
+    layers: &[(&str, &Image2D)],
+    filename: &std::path::Path,
+    precision: ExrPrecision,
+    tone_mapping: &ToneMapping,
+) -> Result<(), Error> {
+    let stem = filename.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let temp = filename.with_file_name(format!(".{}.tmp.{}", stem, extension(filename)));
+    save(layers, &temp, precision, tone_mapping)?;
+    for (from, to) in layer_files(layers, &temp).iter().zip(layer_files(layers, filename)) {
+        std::fs::rename(from, to)?;
+    }
+    Ok(())
+}
+
+///
+/// Save the layers to a single OpenEXR file, each one with its own name and R, G and B channels
+pub fn save_exr(layers: &[(&str, &Image2D)], filename: &std::path::Path, precision: ExrPrecision) -> Result<(), Error> {
+    use exr::prelude::*;
+
+    let (height, width) = layers[0].1.dim();
+    let size = Vec2(width, height);
+    let exr_layers: Vec<Layer<AnyChannels<FlatSamples>>> = layers
+        .iter()
+        .map(|(name, image)| {
+            assert!(image.dim() == (height, width));
+            let channel = |label: &str, component: usize| {
+                let values = image.iter().map(|pixel| pixel[component]);
+                let samples = match precision {
+                    ExrPrecision::Half => FlatSamples::F16(values.map(f16::from_f32).collect()),
+                    ExrPrecision::Float => FlatSamples::F32(values.collect()),
+                };
+                AnyChannel::new(label, samples)
+            };
+            let channels = AnyChannels::sort(SmallVec::from_vec(vec![channel("R", 0), channel("G", 1), channel("B", 2)]));
+            Layer::new(size, LayerAttributes::named(*name), Encoding::SMALL_LOSSLESS, channels)
+        })
+        .collect();
+
+    Image::from_layers(ImageAttributes::new(IntegerBounds::from_dimensions(size)), exr_layers)
+        .write()
+        .to_file(filename)
+        .map_err(std::io::Error::other)
+}
+
+pub fn save_png(image: &Image2D, filename: &std::path::Path, tone_mapping: &ToneMapping) -> image::ImageResult<()> {
+    // Bring the image to the range [0, 255]
     let width = image.ncols();
     let height = image.nrows();
-    let mut buf = vec![0; 3 * width * height];
-    for ((y, x), pixel) in image.indexed_iter() {
-        let offset = 3 * (width * y + x);
-        buf[offset + 0] = (255.0 * (scale * pixel.x).powf(gamma)) as u8;
-        buf[offset + 1] = (255.0 * (scale * pixel.y).powf(gamma)) as u8;
-        buf[offset + 2] = (255.0 * (scale * pixel.z).powf(gamma)) as u8;
-    }
+    let buf = tone_mapping.quantize(image);
 
     // Save the image as PNG
     image::save_buffer(
@@ -74,3 +290,169 @@ pub fn save_hdr(image: &Image2D, filename: &std::path::Path) -> image::ImageResu
     let encoder = image::hdr::HDREncoder::new(writer);
     encoder.encode(&buf, width, height)
 }
+
+impl FromStr for ExrPrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half" => Ok(ExrPrecision::Half),
+            "float" => Ok(ExrPrecision::Float),
+            _ => Err(format!("Unknown OpenEXR precision: {}", s)),
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Luminance below which errors are no longer relative, so that black pixels don't need endless samples
+const DARK_PIXEL_FLOOR: f32 = 1e-3;
+
+fn extension(filename: &std::path::Path) -> String {
+    filename.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase()
+}
+
+///
+/// Files written by `save`, one per layer except for OpenEXR
+fn layer_files(layers: &[(&str, &Image2D)], filename: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let extension = extension(filename);
+    if extension == "exr" {
+        return vec![filename.to_path_buf()];
+    }
+
+    let stem = filename.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    layers
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| {
+            if i == 0 {
+                filename.to_path_buf()
+            } else {
+                filename.with_file_name(format!("{}.{}.{}", stem, name, extension))
+            }
+        })
+        .collect()
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Every layer must be read back with its name and its values
+    #[test]
+    fn exr_layers_round_trip() {
+        let beauty = Image2D::from_shape_fn((3, 4), |(y, x)| Color::new(x as f32, y as f32, 0.25));
+        let albedo = Image2D::from_elem((3, 4), Color::new(0.5, 0.125, 1.0));
+        let filename = std::env::temp_dir().join(format!("rspt-layers-{}.exr", std::process::id()));
+
+        for precision in [ExrPrecision::Half, ExrPrecision::Float].iter() {
+            let tone_mapping = ToneMapping::normalized(&beauty);
+            save(&[("beauty", &beauty), ("albedo", &albedo)], &filename, *precision, &tone_mapping).unwrap();
+            let image = exr::prelude::read_all_flat_layers_from_file(&filename).unwrap();
+            assert!(image.layer_data.len() == 2);
+            for (layer, expected) in image.layer_data.iter().zip([&beauty, &albedo].iter()) {
+                for (channel, component) in layer.channel_data.list.iter().zip([2, 1, 0].iter()) {
+                    let values: Vec<f32> = channel.sample_data.values_as_f32().collect();
+                    let expected: Vec<f32> = expected.iter().map(|c| c[*component]).collect();
+                    assert!(values == expected);
+                }
+            }
+            assert!(image.layer_data[1].attributes.layer_name == Some("albedo".into()));
+        }
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    ///
+    /// The running statistics must match the direct mean and variance, and a constant pixel must converge
+    #[test]
+    fn pixel_stats_match_direct_variance() {
+        let values = [0.5, 2.0, 1.25, 0.0, 3.5, 1.0];
+        let mut stats = PixelStats::new(2, 1);
+        for (i, value) in values.iter().enumerate() {
+            for (x, color) in [(0.5, Color::repeat(*value)), (1.5, Color::repeat(0.75))].iter() {
+                stats.add(&FilmSample {
+                    position: nalgebra::Point2::new(*x, 0.1 * i as f32),
+                    color: *color,
+                    aovs: [color::black(); AOV_COUNT],
+                });
+            }
+        }
+
+        let n = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / n;
+        let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / (n - 1.0);
+        assert!((stats.mean[(0, 0)] - mean).abs() < 1e-5);
+        assert!((stats.m2[(0, 0)] / (n - 1.0) - variance).abs() < 1e-5);
+        assert!(stats.relative_error(0, 1) == 0.0);
+        assert!((stats.mean_relative_error() - 0.5 * stats.relative_error(0, 0)).abs() < 1e-6);
+        assert!(PixelStats::new(2, 1).mean_relative_error() == f32::INFINITY);
+        assert!(stats.unconverged(0.01) == ndarray::arr2(&[[true, false]]));
+        assert!(stats.sample_counts()[(0, 1)] == Color::repeat(n));
+    }
+
+    ///
+    /// Pixels left out of a pass must not take in the samples of their neighbours, even with a wide filter
+    #[test]
+    fn inactive_pixels_are_not_splatted() {
+        let mut film = Film::new(3, 1, Filter::Tent);
+        let samples = ndarray::Array2::from_shape_fn((1, 3), |(_, x)| {
+            Some(FilmSample {
+                position: nalgebra::Point2::new(0.9 + 0.6 * x as f32, 0.5),
+                color: Color::repeat(x as f32),
+                aovs: [color::black(); AOV_COUNT],
+            })
+        });
+        let active = ndarray::arr2(&[[true, false, true]]);
+        film.splat_all(&samples, &active);
+        let mut unmasked = Film::new(3, 1, Filter::Tent);
+        unmasked.splat_all(&samples, &ndarray::Array2::from_elem((1, 3), true));
+        assert!(unmasked.weights[(0, 1)] > 0.0);
+
+        assert!(film.weights[(0, 1)] == 0.0);
+        assert!(film.weights[(0, 0)] > 0.0 && film.weights[(0, 2)] > 0.0);
+    }
+
+    ///
+    /// Atomic saves must leave every layer under its final name, and no temporary file behind
+    #[test]
+    fn atomic_save_renames_every_layer() {
+        let dir = std::env::temp_dir().join(format!("rspt-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let beauty = Image2D::from_elem((3, 4), Color::new(0.5, 0.25, 1.0));
+        let depth = Image2D::from_elem((3, 4), Color::repeat(2.0));
+        let tone_mapping = ToneMapping::normalized(&beauty);
+
+        let filename = dir.join("image.png");
+        save_atomically(&[("beauty", &beauty), ("depth", &depth)], &filename, ExrPrecision::Half, &tone_mapping).unwrap();
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert!(files == vec!["image.depth.png".to_string(), "image.png".to_string()], "{:?}", files);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    ///
+    /// Normals must keep their sign in 8-bit files, each component going from [-1, 1] to [0, 255]
+    #[test]
+    fn png_normals_keep_their_sign() {
+        let beauty = Image2D::from_elem((1, 2), Color::repeat(1.0));
+        let normal = ndarray::arr2(&[[Color::new(-1.0, 0.0, 1.0), Color::new(0.28, -0.96, 0.0)]]);
+        let filename = std::env::temp_dir().join(format!("rspt-normals-{}.png", std::process::id()));
+        let layer_file = layer_files(&[("beauty", &beauty), ("normal", &normal)], &filename).pop().unwrap();
+
+        save(&[("beauty", &beauty), ("normal", &normal)], &filename, ExrPrecision::Half, &ToneMapping::linear()).unwrap();
+        let encoded = image::open(&layer_file).unwrap().to_rgb().into_raw();
+        std::fs::remove_file(&filename).unwrap();
+        std::fs::remove_file(&layer_file).unwrap();
+        assert!(encoded == vec![0, 128, 255, 163, 5, 128], "{:?}", encoded);
+    }
+}
@@ -0,0 +1,196 @@
+use super::*;
+use crate::geometry;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// An emissive triangle, in world space
+pub struct AreaLight {
+    pub v1: nalgebra::Point3<f32>,
+    pub v2: nalgebra::Point3<f32>,
+    pub v3: nalgebra::Point3<f32>,
+    pub uv: [nalgebra::Vector2<f32>; 3],
+    pub normal: nalgebra::Vector3<f32>,
+    pub area: f32,
+    pub emission: Param<Color>,
+}
+
+///
+/// Every emissive triangle in the scene, sampled proportionally to its area
+pub struct LightList {
+    pub lights: Vec<AreaLight>,
+    cdf: Vec<f32>,
+    total_area: f32,
+}
+
+///
+/// A point sampled on the surface of a light
+pub struct LightSample {
+    pub point: nalgebra::Point3<f32>,
+    pub normal: nalgebra::Vector3<f32>,
+    pub emission: Color,
+    pub pdf: f32,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl LightList {
+    ///
+    /// Collect the emissive triangles of every instance
+    pub fn new(instances: &[geometry::Instance], materials: &[Material]) -> Self {
+        let mut lights = Vec::new();
+        for instance in instances {
+            let material = &materials[instance.material as usize];
+            let emission = match material {
+                Material::Light { emission } | Material::Standard { emission, .. } if material.is_emissive() => emission,
+                _ => continue,
+            };
+
+            let vertices = &instance.mesh.vertices;
+            for t in &instance.mesh.triangles {
+                let v1 = instance.transform * vertices[t.v1 as usize].coords;
+                let v2 = instance.transform * vertices[t.v2 as usize].coords;
+                let v3 = instance.transform * vertices[t.v3 as usize].coords;
+                let uv = [
+                    vertices[t.v1 as usize].uv,
+                    vertices[t.v2 as usize].uv,
+                    vertices[t.v3 as usize].uv,
+                ];
+                let cross = (v2 - v1).cross(&(v3 - v1));
+                let area = 0.5 * cross.norm();
+                if area > 0.0 {
+                    lights.push(AreaLight {
+                        v1,
+                        v2,
+                        v3,
+                        uv,
+                        normal: cross.normalize(),
+                        area,
+                        emission: emission.clone(),
+                    });
+                }
+            }
+        }
+
+        // Cumulative area, used to pick a triangle
+        let mut cdf = Vec::with_capacity(lights.len());
+        let mut total_area = 0.0;
+        for l in &lights {
+            total_area += l.area;
+            cdf.push(total_area);
+        }
+
+        LightList {
+            lights,
+            cdf,
+            total_area,
+        }
+    }
+
+    ///
+    /// Check if there are any lights at all
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    ///
+    /// Probability density, with respect to area, of sampling any given point of a light
+    pub fn pdf(&self) -> f32 {
+        1.0 / self.total_area
+    }
+
+    ///
+    /// Pick a light with probability proportional to its area, and a uniformly distributed point on it
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let u_light = sampler.next_1d();
+        let u = sampler.next_2d();
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let target = u_light * self.total_area;
+        let index = usize::min(self.cdf.partition_point(|&a| a < target), self.lights.len() - 1);
+        let light = &self.lights[index];
+
+        let r1 = u.x.sqrt();
+        let r2 = u.y;
+        let (b1, b2, b3) = (1.0 - r1, r1 * (1.0 - r2), r1 * r2);
+        let point = light.v1.coords * b1 + light.v2.coords * b2 + light.v3.coords * b3;
+        let uv = light.uv[0] * b1 + light.uv[1] * b2 + light.uv[2] * b3;
+
+        Some(LightSample {
+            point: nalgebra::Point3::from(point),
+            normal: light.normal,
+            emission: light.emission.eval(uv),
+            pdf: self.pdf(),
+        })
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    ///
+    /// Right triangle in the XY plane with legs of the given length, at some height
+    fn triangle_light(leg: f32, height: f32) -> geometry::Instance {
+        let vertex = |x, y| geometry::Vertex {
+            coords: nalgebra::Point3::new(x, y, 0.0),
+            normal: nalgebra::Vector3::z(),
+            uv: nalgebra::zero(),
+        };
+        let mesh = geometry::Mesh::new(
+            vec![vertex(0.0, 0.0), vertex(leg, 0.0), vertex(0.0, leg)],
+            vec![geometry::Triangle {
+                v1: 0,
+                v2: 1,
+                v3: 2,
+                material: 0,
+            }],
+            geometry::BuildStrategy::Sah,
+        );
+        let transform = nalgebra::Matrix4::new_translation(&nalgebra::Vector3::new(0.0, 0.0, height));
+        geometry::Instance::new(Arc::new(mesh), 0, nalgebra::Transform3::from_matrix_unchecked(transform))
+    }
+
+    ///
+    /// The density must integrate to one over the lights, with each light picked in proportion to its area and
+    /// every point falling inside it
+    #[test]
+    fn light_pdf_integrates_to_one() {
+        let materials = vec![Material::Light {
+            emission: Param::Constant(Color::repeat(1.0)),
+        }];
+        let lights = LightList::new(&[triangle_light(1.0, 0.0), triangle_light(2.0, 5.0)], &materials);
+        let total_area: f32 = lights.lights.iter().map(|l| l.area).sum();
+        assert!((total_area - 2.5).abs() < 1e-5);
+        assert!((lights.pdf() * total_area - 1.0).abs() < 1e-5);
+
+        let config = SamplerConfig {
+            kind: SamplerKind::Random,
+            samples_per_pixel: 10000,
+            seed: 1,
+        };
+        let mut on_small = 0;
+        for i in 0..10000 {
+            let sample = lights.sample(config.for_pixel(0, i).as_mut()).unwrap();
+            let leg = if sample.point.z < 1.0 { 1.0 } else { 2.0 };
+            assert!(sample.point.x >= 0.0 && sample.point.y >= 0.0 && sample.point.x + sample.point.y <= leg + 1e-5);
+            assert!(sample.pdf == lights.pdf());
+            if leg == 1.0 {
+                on_small += 1;
+            }
+        }
+        assert!((on_small as f32 / 10000.0 - 0.2).abs() < 0.02);
+    }
+}
@@ -8,10 +8,36 @@ use super::*;
 // Public data types
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 
+///
+/// Surface materials. In all the functions below, `incident` is the direction of the ray that hit the surface,
+/// and `outgoing_ray` the direction of the secondary ray, from where the light arrives.
+/// Parameters of type Param can be textured.
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Material {
-    Light { emission: Color },
-    Standard { emission: Color, diffuse: Color },
+    Light { emission: Param<Color> },
+    Standard { emission: Param<Color>, diffuse: Param<Color> },
+    Mirror { reflectance: Color },
+    Dielectric { ior: f32, transmittance: Color },
+    /// Rough metal, with a complex index of refraction given per channel
+    RoughConductor { eta: Color, k: Color, roughness: Param<f32> },
+    /// Diffuse base under a rough dielectric coating, `specular` being its reflectance at normal incidence
+    RoughPlastic { diffuse: Param<Color>, specular: Color, roughness: Param<f32> },
+    /// Artist-friendly material blending diffuse, metal, clearcoat and glass, see principled.rs
+    Principled {
+        base_color: Param<Color>,
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_roughness")]
+        roughness: Param<f32>,
+        #[serde(default = "default_specular")]
+        specular: f32,
+        #[serde(default)]
+        sheen: f32,
+        #[serde(default)]
+        clearcoat: f32,
+        #[serde(default)]
+        transmission: f32,
+    },
 }
 
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -21,57 +47,330 @@ pub enum Material {
 impl Material {
     pub fn none() -> Self {
         Material::Light {
-            emission: color::black(),
+            emission: Param::Constant(color::black()),
         }
     }
 
-    pub fn spawn_secondary_ray(&self, intersection: &geometry::MeshIntersection) -> geometry::Ray {
+    ///
+    /// Load the images of every textured parameter, with paths relative to `base_dir`
+    pub fn load_textures(&mut self, base_dir: &std::path::Path) -> Result<(), std::io::Error> {
+        match self {
+            Material::Light { emission } => emission.load(base_dir),
+            Material::Standard { emission, diffuse } => {
+                emission.load(base_dir)?;
+                diffuse.load(base_dir)
+            }
+            Material::RoughConductor { roughness, .. } => roughness.load(base_dir),
+            Material::RoughPlastic {
+                diffuse, roughness, ..
+            } => {
+                diffuse.load(base_dir)?;
+                roughness.load(base_dir)
+            }
+            Material::Principled {
+                base_color, roughness, ..
+            } => {
+                base_color.load(base_dir)?;
+                roughness.load(base_dir)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///
+    /// Check if any part of the surface emits light
+    pub fn is_emissive(&self) -> bool {
+        match self {
+            Material::Light { emission } | Material::Standard { emission, .. } => match emission {
+                Param::Constant(c) => *c != color::black(),
+                Param::Texture { .. } => true,
+            },
+            _ => false,
+        }
+    }
+
+    ///
+    /// Light emitted by the surface at some texture coordinates
+    pub fn emission(&self, uv: nalgebra::Vector2<f32>) -> Color {
+        match self {
+            Material::Light { emission } => emission.eval(uv),
+            Material::Standard { emission, .. } => emission.eval(uv),
+            _ => color::black(),
+        }
+    }
+
+    ///
+    /// Overall color of the surface at some texture coordinates, as the denoisers expect it: what it reflects
+    /// or transmits at normal incidence, whatever its roughness
+    pub fn albedo(&self, uv: nalgebra::Vector2<f32>) -> Color {
+        match self {
+            Material::Light { .. } => color::black(),
+            Material::Standard { diffuse, .. } => diffuse.eval(uv),
+            Material::Mirror { reflectance } => *reflectance,
+            Material::Dielectric { transmittance, .. } => *transmittance,
+            Material::RoughConductor { eta, k, .. } => microfacet::fresnel_conductor(1.0, eta, k),
+            Material::RoughPlastic { diffuse, .. } => diffuse.eval(uv),
+            Material::Principled { base_color, .. } => base_color.eval(uv),
+        }
+    }
+
+    ///
+    /// Check if the surface reflects or transmits any light
+    pub fn is_reflective(&self) -> bool {
+        !matches!(self, Material::Light { .. })
+    }
+
+    ///
+    /// Check if the BSDF is a Dirac delta, which can't be evaluated for arbitrary directions,
+    /// and so it's not worth sampling the lights from it
+    pub fn is_specular(&self) -> bool {
+        matches!(self, Material::Mirror { .. } | Material::Dielectric { .. })
+    }
+
+    ///
+    /// Evaluate the BSDF for light arriving from a given direction
+    pub fn eval(
+        &self,
+        point: &geometry::MeshIntersection,
+        incident: nalgebra::Vector3<f32>,
+        outgoing_ray: nalgebra::Vector3<f32>,
+    ) -> Color {
+        let frame = geometry::Frame::new(point.normal);
+        let wo = frame.world_to_local(-incident);
+        let wi = frame.world_to_local(outgoing_ray);
+        match self {
+            Material::Standard { diffuse, .. } => {
+                if point.normal.dot(&outgoing_ray) > 0.0 {
+                    diffuse.eval(point.uv) / f32::consts::PI
+                } else {
+                    color::black()
+                }
+            }
+            Material::RoughConductor { eta, k, roughness } => {
+                let alpha = microfacet::roughness_to_alpha(roughness.eval(point.uv));
+                let cos_h = wo.dot(&(wo + wi).normalize());
+                microfacet::fresnel_conductor(cos_h, eta, k) * microfacet::reflection(wo, wi, alpha)
+            }
+            Material::RoughPlastic {
+                diffuse,
+                specular,
+                roughness,
+            } => {
+                if wo.z <= 0.0 || wi.z <= 0.0 {
+                    return color::black();
+                }
+                let alpha = microfacet::roughness_to_alpha(roughness.eval(point.uv));
+                let cos_h = wo.dot(&(wo + wi).normalize());
+                let coating = microfacet::fresnel_schlick(cos_h, specular) * microfacet::reflection(wo, wi, alpha);
+                coating + plastic_base(&diffuse.eval(point.uv), specular, wo, wi)
+            }
+            Material::Principled { .. } => self.principled(point.uv).eval(wo, wi),
+            _ => color::black(),
+        }
+    }
+
+    ///
+    /// Probability density, with respect to solid angle, of spawn_secondary_ray choosing a given direction
+    pub fn pdf(
+        &self,
+        point: &geometry::MeshIntersection,
+        incident: nalgebra::Vector3<f32>,
+        outgoing_ray: nalgebra::Vector3<f32>,
+    ) -> f32 {
+        let frame = geometry::Frame::new(point.normal);
+        let wo = frame.world_to_local(-incident);
+        let wi = frame.world_to_local(outgoing_ray);
+        match self {
+            Material::Standard { .. } => f32::max(0.0, wi.z) / f32::consts::PI,
+            Material::RoughConductor { roughness, .. } => {
+                microfacet::reflection_pdf(wo, wi, microfacet::roughness_to_alpha(roughness.eval(point.uv)))
+            }
+            Material::RoughPlastic {
+                diffuse,
+                specular,
+                roughness,
+            } => {
+                let p = plastic_coating_probability(&diffuse.eval(point.uv), specular, wo);
+                let alpha = microfacet::roughness_to_alpha(roughness.eval(point.uv));
+                p * microfacet::reflection_pdf(wo, wi, alpha) + (1.0 - p) * f32::max(0.0, wi.z) / f32::consts::PI
+            }
+            Material::Principled { .. } => self.principled(point.uv).pdf(wo, wi),
+            _ => 0.0,
+        }
+    }
+
+    ///
+    /// Choose the direction of the secondary ray. Every material takes the same sample values, one to choose
+    /// between lobes and two to sample a direction from the chosen one.
+    pub fn spawn_secondary_ray(
+        &self,
+        intersection: &geometry::MeshIntersection,
+        incident: nalgebra::Vector3<f32>,
+        sampler: &mut dyn Sampler,
+    ) -> geometry::Ray {
+        let u_lobe = sampler.next_1d();
+        let u = sampler.next_2d();
         match self {
             Material::Light { .. } => geometry::Ray {
                 origin: nalgebra::Point3::new(f32::NAN, f32::NAN, f32::NAN),
                 direction: nalgebra::Vector3::new(f32::NAN, f32::NAN, f32::NAN),
             },
             Material::Standard { .. } => {
-                sample_hemisphere(intersection.point, intersection.normal)
+                sample_hemisphere(intersection.point, intersection.normal, u)
+            }
+            Material::Mirror { .. } => {
+                let d = reflect(incident, intersection.normal);
+                geometry::Ray {
+                    origin: intersection.point + 1.0e-3 * d,
+                    direction: d,
+                }
+            }
+            Material::Dielectric { ior, .. } => {
+                sample_dielectric(intersection.point, intersection.normal, incident, *ior, u_lobe)
+            }
+            Material::RoughConductor { roughness, .. } => {
+                sample_microfacet(intersection.point, intersection.normal, incident, roughness.eval(intersection.uv), u)
+            }
+            Material::RoughPlastic {
+                diffuse,
+                specular,
+                roughness,
+            } => {
+                let frame = geometry::Frame::new(intersection.normal);
+                let wo = frame.world_to_local(-incident);
+                let diffuse = diffuse.eval(intersection.uv);
+                if u_lobe < plastic_coating_probability(&diffuse, specular, wo) {
+                    sample_microfacet(intersection.point, intersection.normal, incident, roughness.eval(intersection.uv), u)
+                } else {
+                    sample_hemisphere(intersection.point, intersection.normal, u)
+                }
+            }
+            Material::Principled { .. } => {
+                let frame = geometry::Frame::new(intersection.normal);
+                let wo = frame.world_to_local(-incident);
+                let d = frame.local_to_world(self.principled(intersection.uv).sample(wo, u_lobe, u));
+                geometry::Ray {
+                    origin: intersection.point + 1.0e-3 * d,
+                    direction: d,
+                }
             }
         }
     }
 
+    ///
+    /// Light reflected by the surface along a ray made by spawn_secondary_ray, emission not included
     pub fn shade(
         &self,
         point: &geometry::MeshIntersection,
+        incident: nalgebra::Vector3<f32>,
         outgoing_ray: nalgebra::Vector3<f32>,
         incoming_color: Color,
     ) -> Color {
         match self {
-            Material::Light { emission } => *emission,
-            Material::Standard { emission, diffuse } => {
-                emission + (point.normal.dot(&outgoing_ray) * diffuse.component_mul(&incoming_color))
+            Material::Mirror { reflectance } => reflectance.component_mul(&incoming_color),
+            // The Fresnel terms cancel out with the probabilities of choosing each direction
+            Material::Dielectric { transmittance, .. } => {
+                let reflected = incident.dot(&point.normal) * outgoing_ray.dot(&point.normal) < 0.0;
+                if reflected {
+                    incoming_color
+                } else {
+                    transmittance.component_mul(&incoming_color)
+                }
+            }
+            _ => {
+                let pdf = self.pdf(point, incident, outgoing_ray);
+                if pdf > 0.0 {
+                    let cosine = point.normal.dot(&outgoing_ray).abs();
+                    let bsdf = self.eval(point, incident, outgoing_ray);
+                    bsdf.component_mul(&incoming_color) * (cosine / pdf)
+                } else {
+                    color::black()
+                }
             }
         }
     }
 }
 
+///
+/// Cosine-weighted direction around the Z axis of the local shading frame [Malley's method]
+pub fn sample_cosine_hemisphere(u: nalgebra::Vector2<f32>) -> nalgebra::Vector3<f32> {
+    let (u1, u2) = (u.x, u.y);
+    let r = u1.sqrt();
+    let theta = 2.0 * f32::consts::PI * u2;
+
+    let x = r * theta.cos();
+    let y = r * theta.sin();
+    let z = (f32::max(0.0, 1.0 - u1)).sqrt();
+    nalgebra::Vector3::new(x, y, z)
+}
+
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 // Private functions
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 
+impl Material {
+    ///
+    /// Lobes of the principled material at some texture coordinates
+    fn principled(&self, uv: nalgebra::Vector2<f32>) -> PrincipledBsdf {
+        match self {
+            Material::Principled {
+                base_color,
+                metallic,
+                roughness,
+                specular,
+                sheen,
+                clearcoat,
+                transmission,
+            } => PrincipledBsdf::new(
+                base_color.eval(uv),
+                *metallic,
+                roughness.eval(uv),
+                *specular,
+                *sheen,
+                *clearcoat,
+                *transmission,
+            ),
+            _ => panic!("Not a principled material"),
+        }
+    }
+}
+
+fn default_roughness() -> Param<f32> {
+    Param::Constant(0.5)
+}
+
+fn default_specular() -> f32 {
+    0.5
+}
+
+///
+/// Cosine-weighted sampling of the hemisphere around the normal
 fn sample_hemisphere(
     point: nalgebra::Point3<f32>,
-    normal: nalgebra::Vector3<f32>
+    normal: nalgebra::Vector3<f32>,
+    u: nalgebra::Vector2<f32>,
 ) -> geometry::Ray {
-    let x = 2.0 * rand::random::<f32>() - 1.0;
-    let y = 2.0 * rand::random::<f32>() - 1.0;
-    let z = 2.0 * rand::random::<f32>() - 1.0;
-    let s = (x * x + y * y + z * z).sqrt();
-
-    let mut d = nalgebra::Vector3::new(x / s, y / s, z / s);
+    let d = geometry::Frame::new(normal).local_to_world(sample_cosine_hemisphere(u));
 
-    if d.dot(&normal) < 0.0 {
-        d.x = -d.x;
-        d.y = -d.y;
-        d.z = -d.z;
+    geometry::Ray {
+        origin: point + 1.0e-3 * d,
+        direction: d,
     }
+}
+
+///
+/// Reflect the incident ray on a microfacet sampled from the visible normals
+fn sample_microfacet(
+    point: nalgebra::Point3<f32>,
+    normal: nalgebra::Vector3<f32>,
+    incident: nalgebra::Vector3<f32>,
+    roughness: f32,
+    u: nalgebra::Vector2<f32>,
+) -> geometry::Ray {
+    let frame = geometry::Frame::new(normal);
+    let wo = frame.world_to_local(-incident);
+    let h = microfacet::sample_vndf(wo, microfacet::roughness_to_alpha(roughness), u);
+    let d = frame.local_to_world(reflect(-wo, h));
 
     geometry::Ray {
         origin: point + 1.0e-3 * d,
@@ -79,31 +378,310 @@ fn sample_hemisphere(
     }
 }
 
-/*fn sample_hemisphere(point: nalgebra::Point3<f32>, normal: nalgebra::Vector3<f32>, incident: geometry::Ray) -> geometry::Ray {
-    // Create the local frame
-    let z_axis : nalgebra::Vector3<f32> = normal.normalize();
-    let y_axis : nalgebra::Vector3<f32> = project_vector_onto_plane(incident.direction.normalize(), normal.normalize()).normalize();
-    let x_axis : nalgebra::Vector3<f32> = y_axis.cross(&z_axis);
-    let frame = nalgebra::Matrix3::from_rows(&[x_axis.transpose(), y_axis.transpose(), z_axis.transpose()]).try_inverse().unwrap();
+///
+/// Energy-conserving diffuse base of the plastic, accounting for the light reflected by the coating
+/// [Ashikhmin-Shirley] An Anisotropic Phong BRDF Model
+fn plastic_base(diffuse: &Color, specular: &Color, wo: nalgebra::Vector3<f32>, wi: nalgebra::Vector3<f32>) -> Color {
+    let fo = 1.0 - (1.0 - 0.5 * wo.z).powi(5);
+    let fi = 1.0 - (1.0 - 0.5 * wi.z).powi(5);
+    let scale = 28.0 / (23.0 * f32::consts::PI) * fo * fi;
+    diffuse.component_mul(&(Color::repeat(1.0) - specular)) * scale
+}
 
-    let u1 = rand::random::<f32>();
-    let u2 = rand::random::<f32>();
-    let r = u1.sqrt();
-    let theta = 2.0 * 3.141592654 * u2;
+///
+/// Probability of sampling the coating instead of the diffuse base, proportional to their approximate albedos
+fn plastic_coating_probability(diffuse: &Color, specular: &Color, wo: nalgebra::Vector3<f32>) -> f32 {
+    let coating = microfacet::fresnel_schlick(f32::max(0.0, wo.z), specular).mean();
+    let base = diffuse.component_mul(&(Color::repeat(1.0) - specular)).mean();
+    if coating + base > 0.0 {
+        coating / (coating + base)
+    } else {
+        0.5
+    }
+}
 
-    let x = r * theta.cos();
-    let y = r * theta.sin();
-    let z = (f32::max(0.0, 1.0 - u1)).sqrt();
-    let d = frame * nalgebra::Vector3::new(x, y, z);
+///
+/// Choose between reflection and refraction with a probability given by the Fresnel equations.
+/// The normal points outside the object, so the ray is entering it if they point in opposite directions.
+fn sample_dielectric(
+    point: nalgebra::Point3<f32>,
+    normal: nalgebra::Vector3<f32>,
+    incident: nalgebra::Vector3<f32>,
+    ior: f32,
+    u_choice: f32,
+) -> geometry::Ray {
+    let entering = incident.dot(&normal) < 0.0;
+    let (facing_normal, eta) = if entering {
+        (normal, 1.0 / ior)
+    } else {
+        (-normal, ior)
+    };
+
+    // Total internal reflection happens when there's no refracted direction
+    let cos_i = -incident.dot(&facing_normal);
+    let d = match refract(incident, facing_normal, eta) {
+        Some(refracted) => {
+            if u_choice < microfacet::fresnel_dielectric(cos_i, 1.0 / eta) {
+                reflect(incident, facing_normal)
+            } else {
+                refracted
+            }
+        }
+        None => reflect(incident, facing_normal),
+    };
 
     geometry::Ray {
         origin: point + 1.0e-3 * d,
-        direction: d
+        direction: d,
     }
 }
 
+///
+/// Mirror a direction around the normal
+fn reflect(incident: nalgebra::Vector3<f32>, normal: nalgebra::Vector3<f32>) -> nalgebra::Vector3<f32> {
+    incident - 2.0 * incident.dot(&normal) * normal
+}
+
+///
+/// Snell's law, with `eta` the ratio between the indices of refraction of the incident and transmitted sides.
+/// The normal must be facing the incident ray. Returns None on total internal reflection.
+fn refract(
+    incident: nalgebra::Vector3<f32>,
+    normal: nalgebra::Vector3<f32>,
+    eta: f32,
+) -> Option<nalgebra::Vector3<f32>> {
+    let cos_i = -incident.dot(&normal);
+    let sin2_t = eta * eta * f32::max(0.0, 1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        None
+    } else {
+        let cos_t = (1.0 - sin2_t).sqrt();
+        Some((eta * incident + (eta * cos_i - cos_t) * normal).normalize())
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn surface() -> geometry::MeshIntersection {
+        geometry::MeshIntersection {
+            distance: 1.0,
+            material: 0,
+            point: nalgebra::Point3::origin(),
+            normal: nalgebra::Vector3::z(),
+            geometric_normal: nalgebra::Vector3::z(),
+            uv: nalgebra::zero(),
+            instance: 0,
+        }
+    }
 
-fn project_vector_onto_plane(vector: nalgebra::Vector3<f32>, plane: nalgebra::Vector3<f32>) -> nalgebra::Vector3<f32> {
-    vector - vector.dot(&plane) * plane
-}*/
+    ///
+    /// Independent uniform numbers, the same on every run
+    fn random_sampler(samples_per_pixel: usize) -> SamplerConfig {
+        SamplerConfig {
+            kind: SamplerKind::Random,
+            samples_per_pixel,
+            seed: 1,
+        }
+    }
+
+    fn incident(cos_theta: f32) -> nalgebra::Vector3<f32> {
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        nalgebra::Vector3::new(sin_theta, 0.0, -cos_theta)
+    }
+
+    ///
+    /// Fraction of the light reflected towards the viewer, estimated by importance sampling the BSDF
+    fn albedo(material: &Material, incident: nalgebra::Vector3<f32>) -> Color {
+        let point = surface();
+        let num_samples = 5000;
+        let config = random_sampler(num_samples);
+        let mut total = color::black();
+        for i in 0..num_samples {
+            let ray = material.spawn_secondary_ray(&point, incident, config.for_pixel(0, i).as_mut());
+            total += material.shade(&point, incident, ray.direction, Color::repeat(1.0));
+        }
+        total / (num_samples as f32)
+    }
+
+    ///
+    /// Integral of the pdf over the sphere, estimated with uniformly distributed directions
+    fn pdf_integral(material: &Material, incident: nalgebra::Vector3<f32>) -> f32 {
+        let point = surface();
+        let num_samples = 100000;
+        let config = random_sampler(num_samples);
+        let mut total = 0.0;
+        for i in 0..num_samples {
+            let u = config.for_pixel(0, i).next_2d();
+            let z = 1.0 - 2.0 * u.x;
+            let r = (1.0 - z * z).sqrt();
+            let phi = 2.0 * f32::consts::PI * u.y;
+            let direction = nalgebra::Vector3::new(r * phi.cos(), r * phi.sin(), z);
+            total += material.pdf(&point, incident, direction);
+        }
+        4.0 * f32::consts::PI * total / (num_samples as f32)
+    }
+
+    #[test]
+    fn rough_conductor_conserves_energy() {
+        // A conductor that reflects everything at any angle
+        for &roughness in &[0.05, 0.3, 0.7, 1.0] {
+            let material = Material::RoughConductor {
+                eta: Color::repeat(0.0),
+                k: Color::repeat(1000.0),
+                roughness: Param::Constant(roughness),
+            };
+            for &cos_theta in &[1.0, 0.7, 0.3, 0.1] {
+                let a = albedo(&material, incident(cos_theta));
+                assert!(a.max() <= 1.01, "albedo {} at roughness {}, cos {}", a.max(), roughness, cos_theta);
+            }
+
+            // Smooth enough surfaces lose almost nothing to masking at normal incidence
+            if roughness < 0.5 {
+                let a = albedo(&material, incident(1.0));
+                assert!(a.min() >= 0.95, "albedo {} at roughness {}", a.min(), roughness);
+            }
+        }
+    }
+
+    #[test]
+    fn rough_plastic_conserves_energy() {
+        for &roughness in &[0.05, 0.3, 0.7, 1.0] {
+            let material = Material::RoughPlastic {
+                diffuse: Param::Constant(Color::repeat(1.0)),
+                specular: Color::repeat(0.04),
+                roughness: Param::Constant(roughness),
+            };
+            for &cos_theta in &[1.0, 0.7, 0.3, 0.1] {
+                let a = albedo(&material, incident(cos_theta));
+                assert!(a.max() <= 1.01, "albedo {} at roughness {}, cos {}", a.max(), roughness, cos_theta);
+            }
+        }
+    }
+
+    #[test]
+    fn principled_conserves_energy() {
+        let white = |metallic, clearcoat| Material::Principled {
+            base_color: Param::Constant(Color::repeat(1.0)),
+            metallic,
+            roughness: Param::Constant(0.5),
+            specular: 0.5,
+            sheen: 0.0,
+            clearcoat,
+            transmission: 0.0,
+        };
+        for material in [white(0.0, 0.0), white(1.0, 0.0), white(0.5, 1.0)].iter() {
+            for &cos_theta in &[1.0, 0.7, 0.3] {
+                let a = albedo(material, incident(cos_theta));
+                assert!(a.max() <= 1.01, "albedo {} at cos {}", a.max(), cos_theta);
+            }
+        }
+    }
+
+    #[test]
+    fn rough_pdfs_integrate_to_one() {
+        let materials = [
+            Material::RoughConductor {
+                eta: Color::repeat(0.2),
+                k: Color::repeat(3.0),
+                roughness: Param::Constant(0.5),
+            },
+            Material::RoughPlastic {
+                diffuse: Param::Constant(Color::repeat(0.5)),
+                specular: Color::repeat(0.04),
+                roughness: Param::Constant(0.5),
+            },
+            Material::Principled {
+                base_color: Param::Constant(Color::repeat(0.8)),
+                metallic: 0.3,
+                roughness: Param::Constant(0.5),
+                specular: 0.5,
+                sheen: 0.5,
+                clearcoat: 0.5,
+                transmission: 0.5,
+            },
+        ];
+        for material in materials.iter() {
+            // Some reflected directions end up below the surface, so the integral can be slightly less than one
+            let integral = pdf_integral(material, incident(0.8));
+            assert!(integral > 0.9 && integral < 1.05, "pdf integral {}", integral);
+        }
+    }
+
+    #[test]
+    fn mirror_reflects_around_the_normal() {
+        let material = Material::Mirror {
+            reflectance: Color::new(0.9, 0.5, 0.1),
+        };
+        let config = random_sampler(1);
+        let wi = incident(0.6);
+        let ray = material.spawn_secondary_ray(&surface(), wi, config.for_pixel(0, 0).as_mut());
+        assert!((ray.direction - nalgebra::Vector3::new(wi.x, wi.y, -wi.z)).norm() < 1e-5);
+        assert!(material.shade(&surface(), wi, ray.direction, Color::repeat(1.0)) == Color::new(0.9, 0.5, 0.1));
+    }
+
+    ///
+    /// A lossless dielectric must return all the light, split between the sides as the Fresnel equations say,
+    /// and bend the transmitted rays as Snell's law does both when entering and leaving the surface
+    #[test]
+    fn lossless_dielectric_conserves_energy() {
+        let ior = 1.5;
+        let material = Material::Dielectric {
+            ior,
+            transmittance: Color::repeat(1.0),
+        };
+        let point = surface();
+        let num_samples = 20000;
+        let config = random_sampler(num_samples);
+        for &entering in &[true, false] {
+            // Stay below the critical angle when leaving, so that both directions are possible
+            for &cos_theta in &[1.0, 0.9, 0.8] {
+                let wi = if entering { incident(cos_theta) } else { -incident(cos_theta) };
+                let (eta, cos_i) = if entering { (ior, cos_theta) } else { (1.0 / ior, -cos_theta) };
+                let sin_i = (1.0 - cos_theta * cos_theta).sqrt();
+
+                let mut reflected = 0;
+                for i in 0..num_samples {
+                    let ray = material.spawn_secondary_ray(&point, wi, config.for_pixel(0, i).as_mut());
+                    assert!(material.shade(&point, wi, ray.direction, Color::repeat(1.0)) == Color::repeat(1.0));
+                    if ray.direction.z * wi.z < 0.0 {
+                        reflected += 1;
+                        assert!((ray.direction - nalgebra::Vector3::new(wi.x, wi.y, -wi.z)).norm() < 1e-5);
+                    } else {
+                        let sin_t = nalgebra::Vector2::new(ray.direction.x, ray.direction.y).norm();
+                        assert!((sin_t - sin_i / eta).abs() < 1e-5, "sin {} for {}", sin_t, sin_i / eta);
+                    }
+                }
+                let fraction = reflected as f32 / num_samples as f32;
+                let fresnel = microfacet::fresnel_dielectric(cos_i, ior);
+                assert!((fraction - fresnel).abs() < 0.01, "reflected {} instead of {}", fraction, fresnel);
+            }
+        }
+    }
+
+    ///
+    /// Light leaving the dense side beyond the critical angle must always be reflected back
+    #[test]
+    fn dielectric_total_internal_reflection() {
+        let ior = 1.5;
+        let material = Material::Dielectric {
+            ior,
+            transmittance: Color::repeat(0.5),
+        };
+        let config = random_sampler(1000);
+        // The critical angle has a sine of 1 / ior
+        let cos_critical = (1.0 - 1.0 / (ior * ior)).sqrt();
+        let wi = -incident(0.9 * cos_critical);
+        assert!(microfacet::fresnel_dielectric(-0.9 * cos_critical, ior) == 1.0);
+        for i in 0..1000 {
+            let ray = material.spawn_secondary_ray(&surface(), wi, config.for_pixel(0, i).as_mut());
+            assert!((ray.direction - nalgebra::Vector3::new(wi.x, wi.y, -wi.z)).norm() < 1e-5);
+            assert!(material.shade(&surface(), wi, ray.direction, Color::repeat(1.0)) == Color::repeat(1.0));
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::f32;
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// GGX / Trowbridge-Reitz microfacet distribution
+//
+// Everything here works in the local shading frame, with the normal along the Z axis.
+// `wo` points towards the viewer and `wi` towards the light, both away from the surface.
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Convert the user-facing roughness into the alpha parameter of the distribution
+pub fn roughness_to_alpha(roughness: f32) -> f32 {
+    f32::max(1.0e-3, roughness * roughness)
+}
+
+///
+/// Density of microfacets with normal `h`
+pub fn ggx_d(h: nalgebra::Vector3<f32>, alpha: f32) -> f32 {
+    if h.z <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let t = h.z * h.z * (a2 - 1.0) + 1.0;
+    a2 / (f32::consts::PI * t * t)
+}
+
+///
+/// Smith's auxiliary function
+fn smith_lambda(w: nalgebra::Vector3<f32>, alpha: f32) -> f32 {
+    let cos2 = w.z * w.z;
+    let tan2 = f32::max(0.0, 1.0 - cos2) / cos2;
+    0.5 * (-1.0 + (1.0 + alpha * alpha * tan2).sqrt())
+}
+
+///
+/// Fraction of the microfacets visible from a direction
+pub fn smith_g1(w: nalgebra::Vector3<f32>, alpha: f32) -> f32 {
+    1.0 / (1.0 + smith_lambda(w, alpha))
+}
+
+///
+/// Height-correlated masking-shadowing function
+pub fn smith_g2(wo: nalgebra::Vector3<f32>, wi: nalgebra::Vector3<f32>, alpha: f32) -> f32 {
+    1.0 / (1.0 + smith_lambda(wo, alpha) + smith_lambda(wi, alpha))
+}
+
+///
+/// Sample a microfacet normal from the distribution of normals visible from `wo`
+/// [Heitz] Sampling the GGX Distribution of Visible Normals
+/// http://jcgt.org/published/0007/04/01/
+pub fn sample_vndf(wo: nalgebra::Vector3<f32>, alpha: f32, u: nalgebra::Vector2<f32>) -> nalgebra::Vector3<f32> {
+    // Stretch the view direction to the hemisphere configuration
+    let vh = nalgebra::Vector3::new(alpha * wo.x, alpha * wo.y, wo.z).normalize();
+
+    // Orthonormal basis
+    let lensq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if lensq > 0.0 {
+        nalgebra::Vector3::new(-vh.y, vh.x, 0.0) / lensq.sqrt()
+    } else {
+        nalgebra::Vector3::new(1.0, 0.0, 0.0)
+    };
+    let t2 = vh.cross(&t1);
+
+    // Sample the projected area
+    let r = u.x.sqrt();
+    let phi = 2.0 * f32::consts::PI * u.y;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * f32::max(0.0, 1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+
+    // Reproject onto the hemisphere and unstretch
+    let nh = p1 * t1 + p2 * t2 + f32::max(0.0, 1.0 - p1 * p1 - p2 * p2).sqrt() * vh;
+    nalgebra::Vector3::new(alpha * nh.x, alpha * nh.y, f32::max(0.0, nh.z)).normalize()
+}
+
+///
+/// Probability density of sample_vndf followed by a reflection producing `wi`
+pub fn reflection_pdf(wo: nalgebra::Vector3<f32>, wi: nalgebra::Vector3<f32>, alpha: f32) -> f32 {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return 0.0;
+    }
+    let h = (wo + wi).normalize();
+    smith_g1(wo, alpha) * ggx_d(h, alpha) / (4.0 * wo.z)
+}
+
+///
+/// Cook-Torrance reflection, without the Fresnel term
+pub fn reflection(wo: nalgebra::Vector3<f32>, wi: nalgebra::Vector3<f32>, alpha: f32) -> f32 {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return 0.0;
+    }
+    let h = (wo + wi).normalize();
+    ggx_d(h, alpha) * smith_g2(wo, wi, alpha) / (4.0 * wo.z * wi.z)
+}
+
+///
+/// Fresnel reflectance of a conductor with complex index of refraction `eta + i k`, per channel
+pub fn fresnel_conductor(cos_i: f32, eta: &Color, k: &Color) -> Color {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    Color::from_fn(|c, _| {
+        let eta2 = eta[c] * eta[c];
+        let k2 = k[c] * k[c];
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_i * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rp + rs)
+    })
+}
+
+///
+/// Schlick's approximation of the Fresnel reflectance, given the reflectance at normal incidence
+pub fn fresnel_schlick(cos_i: f32, f0: &Color) -> Color {
+    let m = f32::max(0.0, 1.0 - cos_i).powi(5);
+    f0 + (Color::repeat(1.0) - f0) * m
+}
+
+///
+/// Fraction of unpolarized light reflected by a dielectric interface, with `eta` the index of refraction of the
+/// far side relative to the near one. A negative cosine means the light arrives from the far side.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let (cos_i, eta) = if cos_i < 0.0 { (-cos_i, 1.0 / eta) } else { (cos_i, eta) };
+    let sin2_t = f32::max(0.0, 1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Rough dielectric interface, reflecting and refracting through the same microfacets
+// [Walter et al.] Microfacet Models for Refraction through Rough Surfaces
+//
+// `eta` is the index of refraction below the surface relative to the one above it.
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Density of visible normals, as sampled by sample_vndf
+fn vndf_pdf(wo: nalgebra::Vector3<f32>, h: nalgebra::Vector3<f32>, alpha: f32) -> f32 {
+    smith_g1(wo, alpha) / wo.z.abs() * ggx_d(h, alpha) * wo.dot(&h).abs()
+}
+
+///
+/// Mirror the configuration below the surface when the viewer is there, so that `wo` is always above it
+fn flip_to_outside(
+    wo: nalgebra::Vector3<f32>,
+    wi: nalgebra::Vector3<f32>,
+    eta: f32,
+) -> (nalgebra::Vector3<f32>, nalgebra::Vector3<f32>, f32) {
+    if wo.z < 0.0 {
+        let flip = nalgebra::Vector3::new(1.0, 1.0, -1.0);
+        (wo.component_mul(&flip), wi.component_mul(&flip), 1.0 / eta)
+    } else {
+        (wo, wi, eta)
+    }
+}
+
+///
+/// Evaluate the BSDF and the sampling density of sample_dielectric, for light coming from `wi` on either side
+pub fn dielectric(wo: nalgebra::Vector3<f32>, wi: nalgebra::Vector3<f32>, alpha: f32, eta: f32) -> (f32, f32) {
+    let (wo, wi, eta) = flip_to_outside(wo, wi, eta);
+    if wo.z == 0.0 || wi.z == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    // Generalized half vector, facing up
+    let reflected = wi.z > 0.0;
+    let etap = if reflected { 1.0 } else { eta };
+    let mut h = (wi * etap + wo).normalize();
+    if h.z < 0.0 {
+        h = -h;
+    }
+
+    // Discard the microfacets seen from behind
+    if wi.dot(&h) * wi.z < 0.0 || wo.dot(&h) * wo.z < 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let fresnel = fresnel_dielectric(wo.dot(&h), eta);
+    let d = ggx_d(h, alpha);
+    let g = smith_g2(wo, wi, alpha);
+    if reflected {
+        let f = d * g * fresnel / (4.0 * wi.z * wo.z).abs();
+        let pdf = vndf_pdf(wo, h, alpha) / (4.0 * wo.dot(&h).abs()) * fresnel;
+        (f, pdf)
+    } else {
+        let denom = wi.dot(&h) + wo.dot(&h) / etap;
+        let denom2 = denom * denom;
+        let f = d * g * (1.0 - fresnel) * (wi.dot(&h) * wo.dot(&h) / (wi.z * wo.z * denom2)).abs() / (etap * etap);
+        let pdf = vndf_pdf(wo, h, alpha) * wi.dot(&h).abs() / denom2 * (1.0 - fresnel);
+        (f, pdf)
+    }
+}
+
+///
+/// Sample a visible microfacet with `u`, and then either reflect or refract through it according to its
+/// Fresnel reflectance, using `u_choice`
+pub fn sample_dielectric(
+    wo: nalgebra::Vector3<f32>,
+    alpha: f32,
+    eta: f32,
+    u: nalgebra::Vector2<f32>,
+    u_choice: f32,
+) -> nalgebra::Vector3<f32> {
+    let (local_wo, _, local_eta) = flip_to_outside(wo, wo, eta);
+    let h = sample_vndf(local_wo, alpha, u);
+    let cos_o = local_wo.dot(&h);
+    let fresnel = fresnel_dielectric(cos_o, local_eta);
+
+    let wi = if u_choice < fresnel {
+        2.0 * cos_o * h - local_wo
+    } else {
+        // Snell's law; total internal reflection can't happen here, since the Fresnel term would have been one
+        let cos_t = (1.0 - f32::max(0.0, 1.0 - cos_o * cos_o) / (local_eta * local_eta)).sqrt();
+        (-local_wo / local_eta + (cos_o / local_eta - cos_t) * h).normalize()
+    };
+
+    if wo.z < 0.0 {
+        nalgebra::Vector3::new(wi.x, wi.y, -wi.z)
+    } else {
+        wi
+    }
+}
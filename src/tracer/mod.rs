@@ -1,20 +1,55 @@
+pub mod aov;
+pub use aov::*;
+
+pub mod budget;
+pub use budget::*;
+
 pub mod camera;
 pub use camera::*;
 
+pub mod checkpoint;
+pub use checkpoint::*;
+
 pub mod color;
 pub use color::*;
 
+pub mod distribution;
+pub use distribution::*;
+
+pub mod environment;
+pub use environment::*;
+
+pub mod filter;
+pub use filter::*;
+
 pub mod halton;
 pub use halton::*;
 
 pub mod image2d;
 pub use image2d::*;
 
+pub mod light;
+pub use light::*;
+
 pub mod material;
 pub use material::*;
 
+pub mod microfacet;
+
+pub mod principled;
+pub use principled::*;
+
+pub mod sampler;
+pub use sampler::*;
+
 pub mod sampling;
 pub use sampling::*;
 
 pub mod scene;
 pub use scene::*;
+
+pub mod texture;
+pub use texture::*;
+
+pub mod tonemap;
+pub use tonemap::*;
@@ -0,0 +1,179 @@
+use std::f32;
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Principled BSDF, loosely following the Disney model
+// [Burley] Physically-Based Shading at Disney
+//
+// The parameters are mapped to four lobes: a diffuse base with sheen, a specular layer that becomes
+// a colored conductor as `metallic` increases, a clearcoat on top, and a rough glass for `transmission`.
+// Like in microfacet.rs, all directions are in the local shading frame.
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+const CLEARCOAT_ROUGHNESS: f32 = 0.25;
+const CLEARCOAT_F0: f32 = 0.04;
+
+///
+/// Principled parameters, converted to the quantities used by each lobe
+pub struct PrincipledBsdf {
+    base_color: Color,
+    roughness: f32,
+    alpha: f32,
+    f0: Color,
+    dielectric_f0: f32,
+    ior: f32,
+    sheen: f32,
+    diffuse_weight: f32,
+    specular_weight: f32,
+    clearcoat_weight: f32,
+    glass_weight: f32,
+}
+
+impl PrincipledBsdf {
+    pub fn new(
+        base_color: Color,
+        metallic: f32,
+        roughness: f32,
+        specular: f32,
+        sheen: f32,
+        clearcoat: f32,
+        transmission: f32,
+    ) -> Self {
+        // `specular` scales the reflectance at normal incidence of dielectrics, 0.5 meaning an IOR of 1.5
+        let dielectric_f0 = 0.08 * specular;
+        let ior = 2.0 / (1.0 - dielectric_f0.sqrt()) - 1.0;
+        let f0 = Color::repeat(dielectric_f0) * (1.0 - metallic) + base_color * metallic;
+
+        let glass_weight = (1.0 - metallic) * transmission;
+        PrincipledBsdf {
+            base_color,
+            roughness,
+            alpha: microfacet::roughness_to_alpha(roughness),
+            f0,
+            dielectric_f0,
+            ior,
+            sheen,
+            diffuse_weight: (1.0 - metallic) * (1.0 - transmission),
+            specular_weight: 1.0 - glass_weight,
+            clearcoat_weight: 0.25 * clearcoat,
+            glass_weight,
+        }
+    }
+
+    ///
+    /// Evaluate the BSDF
+    pub fn eval(&self, wo: nalgebra::Vector3<f32>, wi: nalgebra::Vector3<f32>) -> Color {
+        let mut result = color::black();
+
+        // Reflection lobes
+        if wo.z > 0.0 && wi.z > 0.0 {
+            let h = (wo + wi).normalize();
+            let cos_d = wi.dot(&h);
+
+            // Diffuse with retro-reflection at grazing angles, plus sheen
+            let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+            let fo = 1.0 + (fd90 - 1.0) * schlick_weight(wo.z);
+            let fi = 1.0 + (fd90 - 1.0) * schlick_weight(wi.z);
+            let diffuse = self.base_color * (fo * fi / f32::consts::PI);
+            let sheen = Color::repeat(self.sheen * schlick_weight(cos_d));
+
+            // Unlike in the original model, the base only gets the light that goes through the specular layer,
+            // otherwise a white material reflects more than it receives at grazing angles
+            let transmitted = (1.0 - self.dielectric_fresnel(wo.z)) * (1.0 - self.dielectric_fresnel(wi.z));
+            result += (diffuse + sheen) * (self.diffuse_weight * transmitted);
+
+            // Specular
+            let specular = microfacet::fresnel_schlick(wo.dot(&h), &self.f0) * microfacet::reflection(wo, wi, self.alpha);
+            result += specular * self.specular_weight;
+
+            // Clearcoat
+            let cc_alpha = microfacet::roughness_to_alpha(CLEARCOAT_ROUGHNESS);
+            let cc_fresnel = microfacet::fresnel_schlick(wo.dot(&h), &Color::repeat(CLEARCOAT_F0));
+            result += cc_fresnel * (self.clearcoat_weight * microfacet::reflection(wo, wi, cc_alpha));
+        }
+
+        // Glass, reflecting untinted and transmitting through the base color
+        if self.glass_weight > 0.0 {
+            let (f, _) = microfacet::dielectric(wo, wi, self.alpha, self.ior);
+            let tint = if wo.z * wi.z < 0.0 {
+                self.base_color
+            } else {
+                Color::repeat(1.0)
+            };
+            result += tint * (self.glass_weight * f);
+        }
+
+        result
+    }
+
+    ///
+    /// Probability density of `sample` choosing a given direction
+    pub fn pdf(&self, wo: nalgebra::Vector3<f32>, wi: nalgebra::Vector3<f32>) -> f32 {
+        let [diffuse, specular, clearcoat, glass] = self.lobe_probabilities(wo);
+        let cc_alpha = microfacet::roughness_to_alpha(CLEARCOAT_ROUGHNESS);
+
+        let mut pdf = 0.0;
+        if wo.z > 0.0 && wi.z > 0.0 {
+            pdf += diffuse * wi.z / f32::consts::PI;
+            pdf += specular * microfacet::reflection_pdf(wo, wi, self.alpha);
+            pdf += clearcoat * microfacet::reflection_pdf(wo, wi, cc_alpha);
+        }
+        if glass > 0.0 {
+            pdf += glass * microfacet::dielectric(wo, wi, self.alpha, self.ior).1;
+        }
+        pdf
+    }
+
+    ///
+    /// Choose a lobe with `u_lobe`, and then a direction from it with `u`
+    pub fn sample(&self, wo: nalgebra::Vector3<f32>, u_lobe: f32, u: nalgebra::Vector2<f32>) -> nalgebra::Vector3<f32> {
+        let [diffuse, specular, clearcoat, glass] = self.lobe_probabilities(wo);
+        if u_lobe < diffuse {
+            sample_cosine_hemisphere(u)
+        } else if u_lobe < diffuse + specular {
+            let h = microfacet::sample_vndf(wo, self.alpha, u);
+            2.0 * wo.dot(&h) * h - wo
+        } else if u_lobe < diffuse + specular + clearcoat {
+            let h = microfacet::sample_vndf(wo, microfacet::roughness_to_alpha(CLEARCOAT_ROUGHNESS), u);
+            2.0 * wo.dot(&h) * h - wo
+        } else {
+            // What's left of the lobe choice is uniformly distributed again, and picks reflection or refraction
+            let u_choice = f32::min(1.0, (u_lobe - (1.0 - glass)) / glass);
+            microfacet::sample_dielectric(wo, self.alpha, self.ior, u, u_choice)
+        }
+    }
+
+    ///
+    /// Reflectance of the dielectric specular layer
+    fn dielectric_fresnel(&self, cosine: f32) -> f32 {
+        self.dielectric_f0 + (1.0 - self.dielectric_f0) * schlick_weight(cosine)
+    }
+
+    ///
+    /// Probability of sampling each lobe, roughly proportional to the light it reflects.
+    /// Only the glass can be seen from below the surface.
+    fn lobe_probabilities(&self, wo: nalgebra::Vector3<f32>) -> [f32; 4] {
+        if wo.z <= 0.0 {
+            let glass = if self.glass_weight > 0.0 { 1.0 } else { 0.0 };
+            return [0.0, 0.0, 0.0, glass];
+        }
+
+        let diffuse = self.diffuse_weight * self.base_color.mean();
+        let specular = self.specular_weight * microfacet::fresnel_schlick(wo.z, &self.f0).mean();
+        let clearcoat = self.clearcoat_weight * microfacet::fresnel_schlick(wo.z, &Color::repeat(CLEARCOAT_F0)).x;
+        let glass = self.glass_weight;
+        let total = diffuse + specular + clearcoat + glass;
+        if total > 0.0 {
+            [diffuse / total, specular / total, clearcoat / total, glass / total]
+        } else {
+            [1.0, 0.0, 0.0, 0.0]
+        }
+    }
+}
+
+///
+/// Fresnel-like falloff used by the diffuse and sheen lobes
+fn schlick_weight(cosine: f32) -> f32 {
+    f32::max(0.0, 1.0 - cosine).powi(5)
+}
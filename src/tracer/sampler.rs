@@ -0,0 +1,386 @@
+use std::str::FromStr;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Stream of sample values in [0, 1) for one sample of a pixel. Every call moves on to the next dimension,
+/// so a path asks for its random numbers in the same order at every sample.
+pub trait Sampler {
+    fn next_1d(&mut self) -> f32;
+    fn next_2d(&mut self) -> nalgebra::Vector2<f32>;
+}
+
+///
+/// Available sampling strategies
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    /// Independent uniform random numbers
+    Random,
+    /// Jittered strata, randomly permuted in every pixel and dimension
+    Stratified,
+    /// Halton sequence with random digit scrambling
+    Halton,
+    /// Sobol sequence with hash-based Owen scrambling
+    Sobol,
+}
+
+///
+/// Everything needed to build the sample stream of any pixel
+#[derive(Clone, Copy, Debug)]
+pub struct SamplerConfig {
+    pub kind: SamplerKind,
+    pub samples_per_pixel: usize,
+    pub seed: u64,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SamplerKind::Random),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("Unknown sampler: {}", s)),
+        }
+    }
+}
+
+impl SamplerConfig {
+    ///
+    /// Sampler for the `sample_index`-th sample of a pixel. Each pixel scrambles the sequences differently,
+    /// so that the errors of neighbouring pixels are not correlated.
+    pub fn for_pixel(&self, pixel: usize, sample_index: usize) -> Box<dyn Sampler> {
+        let pixel_seed = hash(self.seed, pixel as u64);
+        let rng = SmallRng::seed_from_u64(hash(pixel_seed, sample_index as u64));
+        match self.kind {
+            SamplerKind::Random => Box::new(RandomSampler { rng }),
+            SamplerKind::Stratified => Box::new(StratifiedSampler {
+                pixel_seed,
+                sample_index,
+                samples_per_pixel: usize::max(1, self.samples_per_pixel),
+                dimension: 0,
+                rng,
+            }),
+            SamplerKind::Halton => Box::new(HaltonSampler {
+                pixel_seed,
+                sample_index,
+                dimension: 0,
+                rng,
+            }),
+            SamplerKind::Sobol => Box::new(SobolSampler {
+                pixel_seed,
+                sample_index: sample_index as u32,
+                dimension: 0,
+            }),
+        }
+    }
+}
+
+///
+/// Mix two values into a well-distributed 64-bit hash
+pub fn hash(a: u64, b: u64) -> u64 {
+    let mut x = a ^ b.wrapping_add(0x9e37_79b9_7f4a_7c15).wrapping_add(a << 6).wrapping_add(a >> 2);
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+struct RandomSampler {
+    rng: SmallRng,
+}
+
+struct StratifiedSampler {
+    pixel_seed: u64,
+    sample_index: usize,
+    samples_per_pixel: usize,
+    dimension: u64,
+    rng: SmallRng,
+}
+
+struct HaltonSampler {
+    pixel_seed: u64,
+    sample_index: usize,
+    dimension: usize,
+    /// Used once the dimensions run out of prime bases
+    rng: SmallRng,
+}
+
+struct SobolSampler {
+    pixel_seed: u64,
+    sample_index: u32,
+    dimension: u32,
+}
+
+/// Largest f32 below one
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// Bases of the Halton sampler, one per dimension
+const PRIMES: [usize; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109,
+    113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223, 227, 229, 233, 239,
+    241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Sampler for RandomSampler {
+    fn next_1d(&mut self) -> f32 {
+        self.rng.gen::<f32>()
+    }
+
+    fn next_2d(&mut self) -> nalgebra::Vector2<f32> {
+        nalgebra::Vector2::new(self.next_1d(), self.next_1d())
+    }
+}
+
+impl StratifiedSampler {
+    ///
+    /// Stratum of the current sample in a dimension. Once all the strata have been used,
+    /// the next round of samples goes through them again in a different order.
+    fn stratum(&self, num_strata: usize) -> usize {
+        let round = (self.sample_index / num_strata) as u64;
+        let seed = hash(hash(self.pixel_seed, self.dimension), round);
+        permute((self.sample_index % num_strata) as u32, num_strata as u32, seed as u32) as usize
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn next_1d(&mut self) -> f32 {
+        let n = self.samples_per_pixel;
+        let stratum = self.stratum(n);
+        self.dimension += 1;
+        f32::min((stratum as f32 + self.rng.gen::<f32>()) / n as f32, ONE_MINUS_EPSILON)
+    }
+
+    ///
+    /// Jittered grid if the number of samples is a square, and otherwise independent strata per axis
+    fn next_2d(&mut self) -> nalgebra::Vector2<f32> {
+        let n = self.samples_per_pixel;
+        let m = (n as f64).sqrt().round() as usize;
+        if m * m != n {
+            return nalgebra::Vector2::new(self.next_1d(), self.next_1d());
+        }
+
+        let cell = self.stratum(n);
+        self.dimension += 1;
+        let x = ((cell % m) as f32 + self.rng.gen::<f32>()) / m as f32;
+        let y = ((cell / m) as f32 + self.rng.gen::<f32>()) / m as f32;
+        nalgebra::Vector2::new(f32::min(x, ONE_MINUS_EPSILON), f32::min(y, ONE_MINUS_EPSILON))
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        if dimension >= PRIMES.len() {
+            return self.rng.gen::<f32>();
+        }
+
+        let mut sequence = HaltonSeq::scrambled(PRIMES[dimension], hash(self.pixel_seed, dimension as u64) | 1);
+        sequence.discard(self.sample_index);
+        f32::min(sequence.next() as f32, ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> nalgebra::Vector2<f32> {
+        nalgebra::Vector2::new(self.next_1d(), self.next_1d())
+    }
+}
+
+///
+/// The dimensions are taken in groups of four from a 4D Sobol sequence. Every group shuffles the order of the
+/// samples differently, which decorrelates them, and every dimension is Owen-scrambled.
+/// [Burley] Practical Hash-based Owen Scrambling
+/// http://www.jcgt.org/published/0009/04/01/
+impl SobolSampler {
+    fn sample(&self, dimension: u32) -> f32 {
+        let group = dimension / 4;
+        let group_seed = hash(self.pixel_seed, group as u64);
+        let index = nested_uniform_scramble(self.sample_index, group_seed as u32);
+        let value = sobol(index, (dimension % 4) as usize);
+        let value = nested_uniform_scramble(value, hash(group_seed, dimension as u64) as u32);
+        f32::min((value >> 8) as f32 / (1u32 << 24) as f32, ONE_MINUS_EPSILON)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn next_1d(&mut self) -> f32 {
+        let value = self.sample(self.dimension);
+        self.dimension += 1;
+        value
+    }
+
+    ///
+    /// Both values come from the same group, so that they are stratified together
+    fn next_2d(&mut self) -> nalgebra::Vector2<f32> {
+        if self.dimension % 4 == 3 {
+            self.dimension += 1;
+        }
+        nalgebra::Vector2::new(self.next_1d(), self.next_1d())
+    }
+}
+
+///
+/// One dimension of the Sobol sequence, as a 32-bit fixed point number
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let directions = &SOBOL_DIRECTIONS[dimension];
+    let mut result = 0;
+    let mut index = index;
+    let mut bit = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= directions[bit];
+        }
+        index >>= 1;
+        bit += 1;
+    }
+    result
+}
+
+/// Direction numbers of the first four dimensions, from the primitive polynomials 1, x + 1, x^2 + x + 1
+/// and x^3 + x + 1 with initial numbers (1), (1), (1, 3) and (1, 3, 1)
+const SOBOL_DIRECTIONS: [[u32; 32]; 4] = [
+    sobol_directions(0, 0, &[]),
+    sobol_directions(1, 0, &[1]),
+    sobol_directions(2, 1, &[1, 3]),
+    sobol_directions(3, 1, &[1, 3, 1]),
+];
+
+///
+/// Bratley-Fox recurrence for a polynomial of degree `s` with coefficients `a`
+const fn sobol_directions(s: usize, a: u32, m: &[u32]) -> [u32; 32] {
+    let mut v = [0u32; 32];
+    let mut k = 0;
+    while k < 32 {
+        if s == 0 {
+            v[k] = 1 << (31 - k);
+        } else if k < s {
+            v[k] = m[k] << (31 - k);
+        } else {
+            v[k] = v[k - s] ^ (v[k - s] >> s);
+            let mut j = 1;
+            while j < s {
+                if (a >> (s - 1 - j)) & 1 != 0 {
+                    v[k] ^= v[k - j];
+                }
+                j += 1;
+            }
+        }
+        k += 1;
+    }
+    v
+}
+
+///
+/// Owen scrambling of the bits of a number, from the most significant one
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+///
+/// Hash in which every bit only depends on the less significant ones
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+///
+/// Pseudo-random permutation of [0, l), chosen by `p`
+/// [Kensler] Correlated Multi-Jittered Sampling
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// The samples of a pixel must cover [0, 1)^2 evenly, checked on a coarse grid
+    fn check_coverage(kind: SamplerKind, num_samples: usize, max_error: f32) {
+        let config = SamplerConfig {
+            kind,
+            samples_per_pixel: num_samples,
+            seed: 7,
+        };
+        for pixel in 0..4 {
+            let mut counts = [[0usize; 4]; 4];
+            for i in 0..num_samples {
+                let u = config.for_pixel(pixel, i).next_2d();
+                assert!(u.x >= 0.0 && u.x < 1.0 && u.y >= 0.0 && u.y < 1.0);
+                counts[(u.y * 4.0) as usize][(u.x * 4.0) as usize] += 1;
+            }
+            let expected = num_samples as f32 / 16.0;
+            for c in counts.iter().flatten() {
+                let error = (*c as f32 - expected).abs() / expected;
+                assert!(error <= max_error, "{:?}: {} samples in a cell, {} expected", kind, c, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn samplers_are_uniform() {
+        check_coverage(SamplerKind::Random, 4096, 0.25);
+        check_coverage(SamplerKind::Stratified, 256, 0.0);
+        check_coverage(SamplerKind::Halton, 4096, 0.05);
+        check_coverage(SamplerKind::Sobol, 256, 0.0);
+    }
+}
@@ -3,12 +3,69 @@ use ndarray::Zip;
 use super::*;
 use crate::geometry;
 
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Radiance arriving at a point of the film, in pixel units
+#[derive(Clone, Copy)]
+pub struct FilmSample {
+    pub position: nalgebra::Point2<f32>,
+    pub color: Color,
+    pub aovs: AovSample,
+}
+
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 // Public functions
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 
-pub fn sample(scene: &Scene, camera: &Camera, max_bounces: usize) -> Image2D {
-    Zip::from(&camera.make_rays()).par_apply_collect(|r| trace_ray(scene, *r, max_bounces))
+impl FilmSample {
+    ///
+    /// Index (y, x) of the pixel the sample falls in, clamped to an image of the given size
+    pub fn pixel(&self, width: usize, height: usize) -> (usize, usize) {
+        let x = usize::min(f32::max(0.0, self.position.x) as usize, width - 1);
+        let y = usize::min(f32::max(0.0, self.position.y) as usize, height - 1);
+        (y, x)
+    }
+}
+
+///
+/// Trace the `sample_index`-th sample of every pixel, through a point of the pixel chosen by the sampler.
+/// A `max_bounces` of zero means unlimited, with Russian roulette ending the paths randomly once they're
+/// `rr_depth` bounces long. Only the requested `aovs` are evaluated, and only the `active` pixels are sampled.
+#[allow(clippy::too_many_arguments)]
+pub fn sample(
+    scene: &Scene,
+    camera: &Camera,
+    sampler: &SamplerConfig,
+    sample_index: usize,
+    max_bounces: usize,
+    rr_depth: usize,
+    aovs: &[Aov],
+    active: &ndarray::Array2<bool>,
+) -> ndarray::Array2<Option<FilmSample>> {
+    let pixels = ndarray::Array2::from_shape_fn([camera.height, camera.width], |(y, x)| (x, y));
+    Zip::from(&pixels).and(active).par_apply_collect(|&(x, y), &active| {
+        if !active {
+            return None;
+        }
+        let mut pixel_sampler = sampler.for_pixel(y * camera.width + x, sample_index);
+        let offset = pixel_sampler.next_2d();
+        let u_lens = pixel_sampler.next_2d();
+        let position = nalgebra::Point2::new(x as f32 + offset.x, y as f32 + offset.y);
+        Some(match camera.make_ray(position, u_lens) {
+            Some(ray) => {
+                let (color, aovs) = trace_ray(scene, ray, pixel_sampler.as_mut(), max_bounces, rr_depth, aovs);
+                FilmSample { position, color, aovs }
+            }
+            None => FilmSample {
+                position,
+                color: color::black(),
+                aovs: [color::black(); AOV_COUNT],
+            },
+        })
+    })
 
     //let rays = camera.make_rays();
     //sample_scene(scene, &rays, max_bounces)
@@ -29,18 +86,421 @@ pub fn sample(scene: &Scene, camera: &Camera, max_bounces: usize) -> Image2D {
     }
 }*/
 
-fn trace_ray(scene: &Scene, ray: geometry::Ray, max_bounces: usize) -> Color {
-    if max_bounces == 0 {
-        color::black()
-    } else {
+///
+/// Follow a path through the scene, keeping track of the fraction of the light that makes it back to the camera.
+/// The requested output variables are evaluated at the first hit along the way.
+fn trace_ray(
+    scene: &Scene,
+    ray: geometry::Ray,
+    sampler: &mut dyn Sampler,
+    max_bounces: usize,
+    rr_depth: usize,
+    aovs: &[Aov],
+) -> (Color, AovSample) {
+    let mut radiance = color::black();
+    let mut aov_values = [color::black(); AOV_COUNT];
+    let mut throughput = Color::repeat(1.0);
+    let mut ray = ray;
+
+    // Density with which the previous vertex chose the ray, or None if the lights couldn't be
+    // sampled from there (e.g. for camera rays)
+    let mut bsdf_pdf = None;
+
+    let mut depth = 0;
+    loop {
         let hit = scene.intersect(ray);
-        if hit.distance.is_finite() {
-            let material = &scene.materials[hit.material as usize];
-            let outgoing_ray = material.spawn_secondary_ray(&hit);
-            let incoming_color = trace_ray(scene, outgoing_ray, max_bounces - 1);
-            material.shade(&hit, outgoing_ray.direction, incoming_color)
+        if depth == 0 && !aovs.is_empty() {
+            aov_values = hit_aovs(scene, &hit, aovs);
+        }
+        if !hit.distance.is_finite() {
+            radiance += throughput.component_mul(&environment_light(scene, ray, bsdf_pdf));
+            break;
+        }
+
+        let material = &scene.materials[hit.material as usize];
+        radiance += throughput.component_mul(&emitted_light(scene, material, ray, &hit, bsdf_pdf));
+        if !material.is_reflective() {
+            break;
+        }
+
+        // The path is not allowed to be one bounce longer
+        depth += 1;
+        if max_bounces > 0 && depth >= max_bounces {
+            break;
+        }
+
+        // Direct lighting
+        if !material.is_specular() {
+            let direct = sample_lights(scene, material, ray, &hit, sampler)
+                + sample_environment(scene, material, ray, &hit, sampler);
+            radiance += throughput.component_mul(&direct);
+        }
+
+        // Continue with the indirect lighting
+        let outgoing_ray = material.spawn_secondary_ray(&hit, ray.direction, sampler);
+        bsdf_pdf = if material.is_specular() {
+            None
         } else {
-            color::black()
+            Some(material.pdf(&hit, ray.direction, outgoing_ray.direction))
+        };
+        throughput = material.shade(&hit, ray.direction, outgoing_ray.direction, throughput);
+        ray = outgoing_ray;
+
+        // Russian roulette: end the path with a probability that grows as it carries less light,
+        // compensating the survivors. It never survives for sure, so closed mirror boxes terminate.
+        let u_survival = sampler.next_1d();
+        if depth >= rr_depth {
+            let survival = f32::min(0.95, throughput.max());
+            if u_survival >= survival {
+                break;
+            }
+            throughput /= survival;
         }
+        if throughput == color::black() {
+            break;
+        }
+    }
+
+    (radiance, aov_values)
+}
+
+///
+/// Light arriving from the environment along a ray that missed everything, weighted like emitted_light
+fn environment_light(scene: &Scene, ray: geometry::Ray, bsdf_pdf: Option<f32>) -> Color {
+    let radiance = scene.environment.radiance(ray.direction);
+    match bsdf_pdf {
+        Some(bsdf_pdf) if radiance != color::black() => {
+            radiance * power_heuristic(bsdf_pdf, scene.environment.pdf(ray.direction))
+        }
+        _ => radiance,
+    }
+}
+
+///
+/// Light emitted by a surface hit by BSDF sampling, weighted against the chance of light sampling finding it.
+/// The density of light sampling uses the flat triangle, as sample_lights does, for the weights to add up to one.
+fn emitted_light(
+    scene: &Scene,
+    material: &Material,
+    ray: geometry::Ray,
+    hit: &geometry::MeshIntersection,
+    bsdf_pdf: Option<f32>,
+) -> Color {
+    let emission = material.emission(hit.uv);
+    match bsdf_pdf {
+        Some(bsdf_pdf) if emission != color::black() => {
+            let cos_light = hit.geometric_normal.dot(&ray.direction).abs();
+            let light_pdf = scene.lights.pdf() * hit.distance * hit.distance / cos_light;
+            emission * power_heuristic(bsdf_pdf, light_pdf)
+        }
+        _ => emission,
+    }
+}
+
+///
+/// Next-event estimation: light arriving directly from a point sampled on the lights,
+/// weighted against the chance of BSDF sampling finding the same point
+fn sample_lights(
+    scene: &Scene,
+    material: &Material,
+    ray: geometry::Ray,
+    hit: &geometry::MeshIntersection,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let light = match scene.lights.sample(sampler) {
+        Some(light) => light,
+        None => return color::black(),
+    };
+
+    let offset = light.point - hit.point;
+    let distance2 = offset.norm_squared();
+    let direction = offset / distance2.sqrt();
+    // The BSDF decides whether light can arrive from behind the surface
+    let cos_surface = hit.normal.dot(&direction).abs();
+    let cos_light = light.normal.dot(&direction).abs();
+    if cos_light <= 0.0 || !scene.visible(hit.point, light.point) {
+        return color::black();
+    }
+
+    // Convert the density to solid angle, to compare it with the BSDF density
+    let light_pdf = light.pdf * distance2 / cos_light;
+    let weight = power_heuristic(light_pdf, material.pdf(hit, ray.direction, direction));
+
+    let bsdf = material.eval(hit, ray.direction, direction);
+    bsdf.component_mul(&light.emission) * (weight * cos_surface / light_pdf)
+}
+
+///
+/// Next-event estimation towards the environment, with a direction chosen according to its brightness
+fn sample_environment(
+    scene: &Scene,
+    material: &Material,
+    ray: geometry::Ray,
+    hit: &geometry::MeshIntersection,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let light = match scene.environment.sample(sampler) {
+        Some(light) => light,
+        None => return color::black(),
+    };
+    if !scene.escapes(hit.point, light.direction) {
+        return color::black();
+    }
+
+    let weight = power_heuristic(light.pdf, material.pdf(hit, ray.direction, light.direction));
+    let cos_surface = hit.normal.dot(&light.direction).abs();
+    let bsdf = material.eval(hit, ray.direction, light.direction);
+    bsdf.component_mul(&light.radiance) * (weight * cos_surface / light.pdf)
+}
+
+///
+/// Multiple importance sampling weight of the first strategy against the second one
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    ///
+    /// A closed unit cube, with its faces pointing inwards
+    fn closed_box() -> geometry::Mesh {
+        let mut vertices = Vec::new();
+        let mut faces = Vec::new();
+        for axis in 0..3 {
+            for &side in &[0.0, 1.0] {
+                let mut normal = nalgebra::Vector3::zeros();
+                normal[axis] = 1.0 - 2.0 * side;
+                let base = vertices.len() as u32;
+                for &(u, v) in &[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+                    let mut coords = nalgebra::Point3::origin();
+                    coords[axis] = side;
+                    coords[(axis + 1) % 3] = u;
+                    coords[(axis + 2) % 3] = v;
+                    vertices.push(geometry::Vertex {
+                        coords,
+                        normal,
+                        uv: nalgebra::Vector2::new(u, v),
+                    });
+                }
+                for &(a, b, c) in &[(0, 1, 2), (0, 2, 3)] {
+                    faces.push(geometry::Triangle {
+                        v1: base + a,
+                        v2: base + b,
+                        v3: base + c,
+                        material: 0,
+                    });
+                }
+            }
+        }
+        geometry::Mesh::new(vertices, faces, geometry::BuildStrategy::Sah)
+    }
+
+    ///
+    /// Closed box that emits E and reflects a fraction R of the light
+    fn furnace(emission: f32, albedo: f32) -> Scene {
+        let materials = vec![Material::Standard {
+            emission: Param::Constant(Color::repeat(emission)),
+            diffuse: Param::Constant(Color::repeat(albedo)),
+        }];
+        let instance = geometry::Instance::new(
+            Arc::new(closed_box()),
+            0,
+            nalgebra::Transform3::identity(),
+        );
+        let world = geometry::World::new(vec![instance], geometry::BuildStrategy::Sah);
+        let lights = LightList::new(&world.instances, &materials);
+        Scene {
+            materials,
+            geometry: world,
+            lights,
+            environment: Environment::none(),
+        }
+    }
+
+    ///
+    /// Average radiance arriving at the center of a scene
+    fn radiance_at_center(scene: &Scene, max_bounces: usize) -> Color {
+        let num_samples = 4000;
+        let config = SamplerConfig {
+            kind: SamplerKind::Random,
+            samples_per_pixel: num_samples,
+            seed: 1,
+        };
+        let mut total = color::black();
+        for i in 0..num_samples {
+            let mut sampler = config.for_pixel(0, i);
+            let u = sampler.next_2d();
+            let z = 1.0 - 2.0 * u.x;
+            let r = (1.0 - z * z).sqrt();
+            let phi = 2.0 * std::f32::consts::PI * u.y;
+            let ray = geometry::Ray {
+                origin: nalgebra::Point3::new(0.5, 0.5, 0.5),
+                direction: nalgebra::Vector3::new(r * phi.cos(), r * phi.sin(), z),
+            };
+            total += trace_ray(scene, ray, sampler.as_mut(), max_bounces, 3, &[]).0;
+        }
+        total / (num_samples as f32)
+    }
+
+    ///
+    /// White furnace: inside the box every path picks up E * R^k at its k-th bounce,
+    /// whatever the direction it takes
+    #[test]
+    fn white_furnace() {
+        let (emission, albedo) = (0.5, 0.8);
+        let max_bounces = 6;
+        let radiance = radiance_at_center(&furnace(emission, albedo), max_bounces);
+
+        let expected: f32 = (0..max_bounces).map(|k| emission * albedo.powi(k as i32)).sum();
+        for c in radiance.iter() {
+            assert!((c - expected).abs() < 0.02 * expected, "{} != {}", c, expected);
+        }
+    }
+
+    ///
+    /// With unlimited bounces, Russian roulette must still converge to the whole series E / (1 - R)
+    #[test]
+    fn white_furnace_unbounded() {
+        let (emission, albedo) = (0.5, 0.8);
+        let radiance = radiance_at_center(&furnace(emission, albedo), 0);
+
+        let expected = emission / (1.0 - albedo);
+        for c in radiance.iter() {
+            assert!((c - expected).abs() < 0.05 * expected, "{} != {}", c, expected);
+        }
+    }
+
+    ///
+    /// The same seed must give the same image, and a different seed a different one
+    #[test]
+    fn seeded_rendering_is_deterministic() {
+        let scene = furnace(0.5, 0.8);
+        let camera = Camera {
+            position: nalgebra::Point3::new(0.5, 0.5, 0.5),
+            orientation: nalgebra::UnitQuaternion::identity(),
+            width: 8,
+            height: 8,
+            focal: 1.0,
+            aspect: 1.0,
+            aperture: 0.05,
+            focus_distance: 0.5,
+            autofocus: false,
+            bokeh: Bokeh::Disk,
+            projection: Projection::Perspective,
+        };
+        let render = |seed: u64| {
+            let config = SamplerConfig {
+                kind: SamplerKind::Sobol,
+                samples_per_pixel: 4,
+                seed,
+            };
+            let mut film = Film::new(camera.width, camera.height, Filter::Box);
+            let active = ndarray::Array2::from_elem((camera.height, camera.width), true);
+            for i in 0..config.samples_per_pixel {
+                film.splat_all(&sample(&scene, &camera, &config, i, 0, 3, &[], &active), &active);
+            }
+            film.image()
+        };
+
+        assert!(render(7) == render(7));
+        assert!(render(7) != render(8));
+    }
+
+    ///
+    /// Square in the XY plane, centered at the origin, with the given side
+    fn square(side: f32) -> geometry::Mesh {
+        let vertex = |x: f32, y: f32| geometry::Vertex {
+            coords: nalgebra::Point3::new(x * side, y * side, 0.0),
+            normal: nalgebra::Vector3::z(),
+            uv: nalgebra::Vector2::new(x + 0.5, y + 0.5),
+        };
+        let vertices = vec![vertex(-0.5, -0.5), vertex(0.5, -0.5), vertex(0.5, 0.5), vertex(-0.5, 0.5)];
+        let triangle = |v1, v2, v3| geometry::Triangle { v1, v2, v3, material: 0 };
+        geometry::Mesh::new(vertices, vec![triangle(0, 1, 2), triangle(0, 2, 3)], geometry::BuildStrategy::Sah)
+    }
+
+    ///
+    /// Diffuse floor lit by a small square light hanging above it
+    fn small_light() -> Scene {
+        let materials = vec![
+            Material::Standard {
+                emission: Param::Constant(color::black()),
+                diffuse: Param::Constant(Color::repeat(0.5)),
+            },
+            Material::Light {
+                emission: Param::Constant(Color::repeat(4.0)),
+            },
+        ];
+        let lift = nalgebra::Transform3::from_matrix_unchecked(nalgebra::Matrix4::new_translation(&nalgebra::Vector3::z()));
+        let instances = vec![
+            geometry::Instance::new(Arc::new(square(10.0)), 0, nalgebra::Transform3::identity()),
+            geometry::Instance::new(Arc::new(square(0.5)), 1, lift),
+        ];
+        let world = geometry::World::new(instances, geometry::BuildStrategy::Sah);
+        let lights = LightList::new(&world.instances, &materials);
+        Scene {
+            materials,
+            geometry: world,
+            lights,
+            environment: Environment::none(),
+        }
+    }
+
+    ///
+    /// Direct lighting with light sampling and MIS must match the estimate of BSDF sampling alone
+    #[test]
+    fn light_sampling_matches_bsdf_sampling() {
+        let scene = small_light();
+        let ray = geometry::Ray {
+            origin: nalgebra::Point3::new(2.0, 0.0, 0.5),
+            direction: nalgebra::Vector3::new(-1.9, 0.0, -0.5).normalize(),
+        };
+        let hit = scene.intersect(ray);
+        let floor = &scene.materials[hit.material as usize];
+        assert!(hit.material == 0 && (hit.point - nalgebra::Point3::new(0.1, 0.0, 0.0)).norm() < 1e-4);
+
+        let num_samples = 200000;
+        let config = SamplerConfig {
+            kind: SamplerKind::Random,
+            samples_per_pixel: num_samples,
+            seed: 1,
+        };
+        let mut reference = color::black();
+        for i in 0..num_samples {
+            let mut sampler = config.for_pixel(0, i);
+            let outgoing = floor.spawn_secondary_ray(&hit, ray.direction, sampler.as_mut());
+            let light_hit = scene.intersect(outgoing);
+            if light_hit.distance.is_finite() {
+                let emission = scene.materials[light_hit.material as usize].emission(light_hit.uv);
+                reference += floor.shade(&hit, ray.direction, outgoing.direction, emission);
+            }
+        }
+        reference /= num_samples as f32;
+
+        // Two bounces: the floor, then the light, found either by light or by BSDF sampling
+        let num_samples = 20000;
+        let mut estimate = color::black();
+        for i in 0..num_samples {
+            estimate += trace_ray(&scene, ray, config.for_pixel(1, i).as_mut(), 2, 3, &[]).0;
+        }
+        estimate /= num_samples as f32;
+
+        // A small light right above a white-ish floor: about albedo / pi * E * A / h^2
+        assert!((reference.x - 0.5 / std::f32::consts::PI * 4.0 * 0.25).abs() < 0.02);
+        assert!((estimate.x - reference.x).abs() < 0.03 * reference.x, "{} != {}", estimate.x, reference.x);
     }
 }
@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::ops::Deref;
 use std::path::Path;
+use std::sync::Arc;
 
 use super::*;
 use crate::geometry;
@@ -12,25 +13,48 @@ use crate::geometry;
 // Public data types
 // --------------------------------------------------------------------------------------------------------------------------------------------------
 
+///
+/// Object-to-world transform of an instance
+#[derive(Default, Serialize, Deserialize)]
+pub enum TransformDef {
+    #[default]
+    Identity,
+    /// Scale, then rotate (Euler angles in degrees around X, Y and Z, in that order), then translate
+    Components {
+        #[serde(default = "zero_vector")]
+        translation: nalgebra::Vector3<f32>,
+        #[serde(default = "zero_vector")]
+        rotation: nalgebra::Vector3<f32>,
+        #[serde(default = "unit_vector")]
+        scale: nalgebra::Vector3<f32>,
+    },
+    /// Full 4x4 matrix, given as 16 values row by row
+    Matrix(nalgebra::Matrix4<f32>),
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct InstanceDef {
     pub mesh: String,
-    pub material: String, /*,
-                          pub transform: linalg::Matrix4*/
+    pub material: String,
+    #[serde(default)]
+    pub transform: TransformDef,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SceneDef {
     pub materials: HashMap<String, Material>,
     pub meshes: Vec<InstanceDef>,
+    #[serde(default)]
+    pub environment: Option<EnvironmentDef>,
 }
 
 ///
 /// World geometry
 pub struct Scene {
     pub materials: Vec<Material>,
-    //pub geometry: geometry::BVH,
-    pub geometry: Vec<geometry::Mesh>,
+    pub geometry: geometry::World,
+    pub lights: LightList,
+    pub environment: Environment,
 }
 
 // --------------------------------------------------------------------------------------------------------------------------------------------------
@@ -39,49 +63,190 @@ pub struct Scene {
 
 // TODO: check keys
 impl Scene {
-    pub fn from_json(filename: &Path) -> Result<Self, std::io::Error> {
+    pub fn from_json(
+        filename: &Path,
+        strategy: geometry::BuildStrategy,
+    ) -> Result<Self, std::io::Error> {
         // Load the scene description from the JSON file
         let file = File::open(filename)?;
-        let json: SceneDef = ron::de::from_reader(file).unwrap();
+        let mut json: SceneDef = ron::de::from_reader(file).unwrap();
 
-        // Load each model
+        // Load the textures, which are shared by every copy of the material
         let base_dir = filename.parent().unwrap();
+        for material in json.materials.values_mut() {
+            material.load_textures(base_dir)?;
+        }
+
+        // Load each model. Every material is added once, so that the instances sharing it share its index.
         let mut materials = Vec::new();
+        let mut material_ids: HashMap<&str, u32> = HashMap::new();
 
-        let mut meshes = Vec::new();
-        for m in &json.meshes {
-            let material_num = materials.len();
-            materials.push(json.materials.get(&m.material).unwrap().clone());
+        // Each file is loaded only once, and its tree is shared by all of its instances
+        let mut meshes: HashMap<&str, Arc<geometry::Mesh>> = HashMap::new();
+        let mut instances = Vec::new();
+        for (i, m) in json.meshes.iter().enumerate() {
+            let transform = m.transform.to_transform();
+            if transform.try_inverse().is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid scene: the transform of instance {} ({}) can't be inverted", i, m.mesh),
+                ));
+            }
+            let material_num = *material_ids.entry(m.material.as_str()).or_insert_with(|| {
+                materials.push(json.materials.get(&m.material).unwrap().clone());
+                (materials.len() - 1) as u32
+            });
 
-            let mesh_path = base_dir.join(&m.mesh);
-            let mesh = geometry::Mesh::load_ply(mesh_path.deref(), material_num as u32)?;
-            meshes.push(mesh);
+            if !meshes.contains_key(m.mesh.as_str()) {
+                let mesh_path = base_dir.join(&m.mesh);
+                let mesh = geometry::Mesh::load_ply(mesh_path.deref(), 0, strategy)?;
+                meshes.insert(&m.mesh, Arc::new(mesh));
+            }
+            let mesh = meshes[m.mesh.as_str()].clone();
+            instances.push(geometry::Instance::new(mesh, material_num, transform));
         }
 
         // Build the acceleration structure
-        //let tree = geometry::BVH::build_world(meshes);
+        let world = geometry::World::new(instances, strategy);
+        let lights = LightList::new(&world.instances, &materials);
+        let environment = match &json.environment {
+            Some(def) => Environment::new(def, base_dir)?,
+            None => Environment::none(),
+        };
 
         // Done
         Ok(Scene {
             materials: materials,
-            geometry: meshes,
+            geometry: world,
+            lights,
+            environment,
         })
     }
 
     ///
     /// Intersect a ray against the world
     pub fn intersect(&self, ray: geometry::Ray) -> geometry::MeshIntersection {
-        let mut result = geometry::MeshIntersection::empty();
-
         if ray.direction.x.is_finite() && ray.direction.y.is_finite() && ray.direction.z.is_finite() {
-            for m in &self.geometry {
-                let hit = m.intersect(ray);
-                if hit.distance < result.distance {
-                    result = hit;
-                }
+            self.geometry.intersect(ray)
+        } else {
+            geometry::MeshIntersection::empty()
+        }
+    }
+
+    ///
+    /// Check that nothing blocks the segment between two points
+    pub fn visible(&self, from: nalgebra::Point3<f32>, to: nalgebra::Point3<f32>) -> bool {
+        // Both ends are nudged inwards so the surfaces they lie on aren't hit
+        let distance = (to - from).norm();
+        let direction = (to - from) / distance;
+        let ray = geometry::Ray {
+            origin: from + 1.0e-3 * direction,
+            direction,
+        };
+        self.intersect(ray).distance >= distance - 2.0e-3
+    }
+
+    ///
+    /// Check that a ray leaving a point reaches the environment
+    pub fn escapes(&self, from: nalgebra::Point3<f32>, direction: nalgebra::Vector3<f32>) -> bool {
+        let ray = geometry::Ray {
+            origin: from + 1.0e-3 * direction,
+            direction,
+        };
+        !self.intersect(ray).distance.is_finite()
+    }
+}
+
+impl TransformDef {
+    ///
+    /// Build the object-to-world transform
+    pub fn to_transform(&self) -> nalgebra::Transform3<f32> {
+        match self {
+            TransformDef::Identity => nalgebra::Transform3::identity(),
+            TransformDef::Components {
+                translation,
+                rotation,
+                scale,
+            } => {
+                let r = rotation.map(f32::to_radians);
+                let matrix = nalgebra::Matrix4::new_translation(translation)
+                    * nalgebra::UnitQuaternion::from_euler_angles(r.x, r.y, r.z).to_homogeneous()
+                    * nalgebra::Matrix4::new_nonuniform_scaling(scale);
+                nalgebra::Transform3::from_matrix_unchecked(matrix)
             }
+            // The matrix is deserialized column by column, so undo that
+            TransformDef::Matrix(m) => nalgebra::Transform3::from_matrix_unchecked(m.transpose()),
         }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+fn zero_vector() -> nalgebra::Vector3<f32> {
+    nalgebra::zero()
+}
+
+fn unit_vector() -> nalgebra::Vector3<f32> {
+    nalgebra::Vector3::new(1.0, 1.0, 1.0)
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Write a scene file using the floor model of the sample scene
+    fn scene_file(name: &str, instances: &str) -> std::path::PathBuf {
+        let floor = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/models/floor.ply");
+        let text = format!(
+            r#"SceneDef(
+                materials: {{
+                    "white": Standard(diffuse: [0.8, 0.8, 0.8], emission: [0.0, 0.0, 0.0]),
+                    "red": Standard(diffuse: [0.8, 0.0, 0.0], emission: [0.0, 0.0, 0.0]),
+                }},
+                meshes: [{}],
+            )"#,
+            instances.replace("FLOOR", &floor.to_string_lossy())
+        );
+        let filename = std::env::temp_dir().join(format!("rspt-{}-{}.ron", name, std::process::id()));
+        std::fs::write(&filename, text).unwrap();
+        filename
+    }
 
-        return result;
+    ///
+    /// Instances sharing a material must share its index
+    #[test]
+    fn shared_materials_have_one_id() {
+        let filename = scene_file(
+            "materials",
+            r#"InstanceDef(mesh: "FLOOR", material: "white"),
+               InstanceDef(mesh: "FLOOR", material: "red", transform: Components(translation: [0.0, 0.0, 1.0])),
+               InstanceDef(mesh: "FLOOR", material: "white", transform: Components(translation: [0.0, 0.0, 2.0]))"#,
+        );
+        let scene = Scene::from_json(&filename, geometry::BuildStrategy::Sah).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+
+        assert!(scene.materials.len() == 2);
+        let ids: Vec<u32> = scene.geometry.instances.iter().map(|i| i.material).collect();
+        assert!(ids == vec![0, 1, 0]);
+    }
+
+    ///
+    /// Flattening transforms must be reported instead of making the scene unusable
+    #[test]
+    fn singular_transforms_are_rejected() {
+        let filename = scene_file(
+            "singular",
+            r#"InstanceDef(mesh: "FLOOR", material: "white", transform: Components(scale: [0.0, 1.0, 1.0]))"#,
+        );
+        let result = Scene::from_json(&filename, geometry::BuildStrategy::Sah);
+        std::fs::remove_file(&filename).unwrap();
+        assert!(result.err().unwrap().kind() == std::io::ErrorKind::InvalidData);
     }
 }
@@ -0,0 +1,260 @@
+use std::ops::{Add, Mul};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// How texture coordinates outside of [0, 1] are handled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum WrapMode {
+    #[default]
+    Repeat,
+    Mirror,
+    Clamp,
+}
+
+///
+/// Values that can be stored in a texture and filtered
+pub trait Texel: Copy + Add<Output = Self> + Mul<f32, Output = Self> {
+    ///
+    /// Convert a pixel of the image file, `srgb` telling if it's gamma-encoded
+    fn from_pixel(rgb: [f32; 3], srgb: bool) -> Self;
+}
+
+///
+/// Image loaded in memory, with the first row at the top of the image, where v = 1
+pub struct Texture<T> {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<T>,
+    pub wrap: WrapMode,
+}
+
+///
+/// Material parameter, either a constant or a texture looked up with the UV coordinates of the surface.
+/// Textures are written as `(file: "wood.png", wrap: "Mirror")`, with the path relative to the scene file;
+/// the wrap mode is quoted because RON can't tell enum variants apart inside untagged enums.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Param<T> {
+    Constant(T),
+    Texture {
+        file: String,
+        #[serde(default)]
+        wrap: WrapMode,
+        #[serde(skip)]
+        texture: Option<Arc<Texture<T>>>,
+    },
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Texel for Color {
+    fn from_pixel(rgb: [f32; 3], srgb: bool) -> Self {
+        let c = Color::new(rgb[0], rgb[1], rgb[2]);
+        if srgb {
+            c.map(srgb_to_linear)
+        } else {
+            c
+        }
+    }
+}
+
+///
+/// Scalar textures hold data rather than colors, so they are never gamma-decoded
+impl Texel for f32 {
+    fn from_pixel(rgb: [f32; 3], _srgb: bool) -> Self {
+        rgb[0]
+    }
+}
+
+impl<T: Texel> Texture<T> {
+    ///
+    /// Load a PNG, JPEG, Radiance HDR or OpenEXR image. 8-bit formats are assumed to be sRGB-encoded.
+    pub fn load(filename: &Path, wrap: WrapMode) -> Result<Self, std::io::Error> {
+        println!("Loading texture {}", filename.display());
+        let extension = filename
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+
+        let (width, height, texels) = match extension.as_str() {
+            "hdr" => {
+                let file = std::io::BufReader::new(std::fs::File::open(filename)?);
+                let decoder = image::hdr::HdrDecoder::new(file).map_err(invalid_data)?;
+                let metadata = decoder.metadata();
+                let pixels = decoder.read_image_hdr().map_err(invalid_data)?;
+                let texels = pixels.iter().map(|p| T::from_pixel(p.0, false)).collect();
+                (metadata.width as usize, metadata.height as usize, texels)
+            }
+            "exr" => {
+                let image = exr::prelude::read_first_rgba_layer_from_file(
+                    filename,
+                    |resolution, _| (resolution.width(), vec![[0.0; 3]; resolution.area()]),
+                    |(width, pixels), position, (r, g, b, _): (f32, f32, f32, f32)| {
+                        pixels[position.y() * *width + position.x()] = [r, g, b];
+                    },
+                )
+                .map_err(invalid_data)?;
+                let size = image.layer_data.size;
+                let (_, pixels) = image.layer_data.channel_data.pixels;
+                let texels = pixels.iter().map(|p| T::from_pixel(*p, false)).collect();
+                (size.width(), size.height(), texels)
+            }
+            _ => {
+                let image = image::open(filename).map_err(invalid_data)?.into_rgb();
+                let texels = image
+                    .pixels()
+                    .map(|p| T::from_pixel([p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0], true))
+                    .collect();
+                (image.width() as usize, image.height() as usize, texels)
+            }
+        };
+
+        Ok(Texture {
+            width,
+            height,
+            texels,
+            wrap,
+        })
+    }
+
+    ///
+    /// Bilinear lookup. V grows upwards, so it's flipped to match the rows of the image.
+    pub fn sample(&self, uv: nalgebra::Vector2<f32>) -> T {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+
+        let top = self.texel(x0 as i64, y0 as i64) * (1.0 - tx) + self.texel(x0 as i64 + 1, y0 as i64) * tx;
+        let bottom = self.texel(x0 as i64, y0 as i64 + 1) * (1.0 - tx) + self.texel(x0 as i64 + 1, y0 as i64 + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    ///
+    /// Texel at integer coordinates, which can be outside of the image
+    pub fn texel(&self, x: i64, y: i64) -> T {
+        let x = wrap_index(x, self.width, self.wrap);
+        let y = wrap_index(y, self.height, self.wrap);
+        self.texels[y * self.width + x]
+    }
+}
+
+impl<T: Texel> Param<T> {
+    ///
+    /// Load the image of a textured parameter, relative to a base directory
+    pub fn load(&mut self, base_dir: &Path) -> Result<(), std::io::Error> {
+        if let Param::Texture { file, wrap, texture } = self {
+            *texture = Some(Arc::new(Texture::load(&base_dir.join(&*file), *wrap)?));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Value of the parameter at some texture coordinates
+    pub fn eval(&self, uv: nalgebra::Vector2<f32>) -> T {
+        match self {
+            Param::Constant(value) => *value,
+            Param::Texture { texture, file, .. } => texture
+                .as_ref()
+                .unwrap_or_else(|| panic!("Texture {} was not loaded", file))
+                .sample(uv),
+        }
+    }
+}
+
+impl<T> From<T> for Param<T> {
+    fn from(value: T) -> Self {
+        Param::Constant(value)
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+fn wrap_index(i: i64, size: usize, wrap: WrapMode) -> usize {
+    let n = size as i64;
+    let wrapped = match wrap {
+        WrapMode::Repeat => i.rem_euclid(n),
+        WrapMode::Mirror => {
+            let i = i.rem_euclid(2 * n);
+            if i < n {
+                i
+            } else {
+                2 * n - 1 - i
+            }
+        }
+        WrapMode::Clamp => i.clamp(0, n - 1),
+    };
+    wrapped as usize
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(wrap: WrapMode) -> Texture<f32> {
+        Texture {
+            width: 2,
+            height: 2,
+            texels: vec![0.0, 1.0, 2.0, 3.0],
+            wrap,
+        }
+    }
+
+    #[test]
+    fn wrap_modes() {
+        let wrapped = |wrap| (-4..7).map(|i| wrap_index(i, 3, wrap)).collect::<Vec<_>>();
+        assert!(wrapped(WrapMode::Repeat) == vec![2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0]);
+        assert!(wrapped(WrapMode::Mirror) == vec![2, 2, 1, 0, 0, 1, 2, 2, 1, 0, 0]);
+        assert!(wrapped(WrapMode::Clamp) == vec![0, 0, 0, 0, 0, 1, 2, 2, 2, 2, 2]);
+    }
+
+    ///
+    /// Texel centers must return their exact value, with the first row at the top, and points in between must be
+    /// interpolated, across the edges as the wrap mode says
+    #[test]
+    fn bilinear_lookup() {
+        let clamp = texture(WrapMode::Clamp);
+        let lookup = |texture: &Texture<f32>, u, v| texture.sample(nalgebra::Vector2::new(u, v));
+        assert!(lookup(&clamp, 0.25, 0.75) == 0.0);
+        assert!(lookup(&clamp, 0.75, 0.75) == 1.0);
+        assert!(lookup(&clamp, 0.25, 0.25) == 2.0);
+        assert!(lookup(&clamp, 0.75, 0.25) == 3.0);
+        assert!(lookup(&clamp, 0.5, 0.5) == 1.5);
+        assert!(lookup(&clamp, 0.5, 0.75) == 0.5);
+
+        assert!(lookup(&clamp, 0.0, 0.75) == 0.0);
+        assert!(lookup(&texture(WrapMode::Repeat), 0.0, 0.75) == 0.5);
+        assert!(lookup(&texture(WrapMode::Mirror), 0.0, 0.75) == 0.0);
+        assert!(lookup(&texture(WrapMode::Repeat), 1.25, 0.0) == 1.0);
+    }
+}
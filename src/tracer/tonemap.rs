@@ -0,0 +1,220 @@
+use std::str::FromStr;
+
+use super::*;
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public data types
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// Curves compressing the radiance of the scene into the [0, 1] range of a display
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMapper {
+    /// Values above one are clipped
+    None,
+    /// L / (1 + L) on the luminance, which keeps the hue
+    Reinhard,
+    /// John Hable's filmic curve from Uncharted 2, with a white point of 11.2
+    Filmic,
+    /// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+    Aces,
+}
+
+///
+/// Everything needed to turn a linear image into 8-bit sRGB
+#[derive(Clone, Copy, Debug)]
+pub struct ToneMapping {
+    pub operator: ToneMapper,
+    /// Exposure compensation in stops, so each unit doubles the brightness
+    pub exposure: f32,
+    /// Add noise of about one quantization step before rounding, which breaks up banding in smooth gradients
+    pub dither: bool,
+    /// Encode with the sRGB curve, as images meant to be looked at are. Data is written linearly instead.
+    pub srgb: bool,
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Public functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl ToneMapping {
+    ///
+    /// Values stored as they are, for data already within [0, 1]
+    pub fn linear() -> Self {
+        ToneMapping {
+            operator: ToneMapper::None,
+            exposure: 0.0,
+            dither: false,
+            srgb: false,
+        }
+    }
+
+    ///
+    /// Plain linear mapping that scales the brightest channel of the image to one, for data like depth
+    pub fn normalized(image: &Image2D) -> Self {
+        let high = image.iter().map(|c| c.max()).fold(0.0, f32::max);
+        ToneMapping {
+            exposure: if high > 0.0 { -high.log2() } else { 0.0 },
+            ..Self::linear()
+        }
+    }
+
+    ///
+    /// Display value of a color, linear and within [0, 1]
+    pub fn apply(&self, color: Color) -> Color {
+        let color = color * self.exposure.exp2();
+        let mapped = match self.operator {
+            ToneMapper::None => color,
+            ToneMapper::Reinhard => {
+                let l = color::luminance(&color);
+                if l > 0.0 {
+                    color / (1.0 + l)
+                } else {
+                    color::black()
+                }
+            }
+            ToneMapper::Filmic => {
+                let white = hable(11.2);
+                color.map(|c| hable(2.0 * c) / white)
+            }
+            ToneMapper::Aces => aces(color),
+        };
+        mapped.map(|c| c.clamp(0.0, 1.0))
+    }
+
+    ///
+    /// Tone map and quantize a whole image to interleaved 8-bit sRGB
+    pub fn quantize(&self, image: &Image2D) -> Vec<u8> {
+        let width = image.ncols();
+        let mut buf = vec![0; 3 * image.len()];
+        for ((y, x), pixel) in image.indexed_iter() {
+            let color = self.apply(*pixel);
+            let offset = 3 * (width * y + x);
+            for c in 0..3 {
+                // Triangular noise, between minus and plus one step, the same in every render
+                let noise = if self.dither {
+                    let h = hash(hash(x as u64, y as u64), c as u64);
+                    let u1 = (h >> 40) as f32 / (1 << 24) as f32;
+                    let u2 = (h & 0xff_ffff) as f32 / (1 << 24) as f32;
+                    u1 + u2 - 1.0
+                } else {
+                    0.0
+                };
+                let encoded = if self.srgb { linear_to_srgb(color[c]) } else { color[c] };
+                buf[offset + c] = (255.0 * encoded + noise).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        buf
+    }
+}
+
+impl FromStr for ToneMapper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ToneMapper::None),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "filmic" => Ok(ToneMapper::Filmic),
+            "aces" => Ok(ToneMapper::Aces),
+            _ => Err(format!("Unknown tone mapping operator: {}", s)),
+        }
+    }
+}
+
+///
+/// sRGB transfer function, from linear values to the encoded ones
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Private functions
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+///
+/// The input matrix goes from sRGB to the rendering space, with the exposure of the fit folded in,
+/// and the output one back to sRGB
+#[rustfmt::skip]
+fn aces(color: Color) -> Color {
+    let input = nalgebra::Matrix3::new(
+        0.59719, 0.35458, 0.04823,
+        0.07600, 0.90834, 0.01566,
+        0.02840, 0.13383, 0.83777,
+    );
+    let output = nalgebra::Matrix3::new(
+        1.60475, -0.53108, -0.07367,
+        -0.10208, 1.10813, -0.00605,
+        -0.00327, -0.07276, 1.07602,
+    );
+    let fit = |v: f32| (v * (v + 0.024_578_6) - 0.000_090_537) / (v * (0.983_729 * v + 0.432_951) + 0.238_081);
+    output * (input * color).map(fit)
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+// Tests
+// --------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Every operator must map black to black, and grow with the radiance without leaving [0, 1]
+    #[test]
+    fn operators_are_monotonic() {
+        for operator in [ToneMapper::None, ToneMapper::Reinhard, ToneMapper::Filmic, ToneMapper::Aces].iter() {
+            let mapping = ToneMapping {
+                operator: *operator,
+                exposure: 1.0,
+                dither: false,
+                srgb: true,
+            };
+            assert!(mapping.apply(color::black()).norm() < 1e-3, "{:?}", operator);
+            let mut previous = -1.0;
+            for i in 0..100 {
+                let value = mapping.apply(Color::repeat(i as f32 * 0.25)).y;
+                assert!(value >= previous && value <= 1.0, "{:?}", operator);
+                previous = value;
+            }
+        }
+    }
+
+    ///
+    /// Dithering moves each value by at most one step
+    #[test]
+    fn dithering_stays_within_a_step() {
+        let image = Image2D::from_shape_fn((16, 16), |(y, x)| Color::repeat((x + 16 * y) as f32 / 256.0));
+        let mut mapping = ToneMapping {
+            operator: ToneMapper::None,
+            exposure: 0.0,
+            dither: false,
+            srgb: true,
+        };
+        let plain = mapping.quantize(&image);
+        mapping.dither = true;
+        let dithered = mapping.quantize(&image);
+        assert!(plain != dithered);
+        for (a, b) in plain.iter().zip(dithered.iter()) {
+            assert!((*a as i32 - *b as i32).abs() <= 1);
+        }
+    }
+
+    ///
+    /// Data layers must be stored linearly, without the sRGB curve
+    #[test]
+    fn normalized_data_is_linear() {
+        let image = Image2D::from_shape_fn((1, 2), |(_, x)| Color::repeat(2.0 * (x + 1) as f32));
+        let mapping = ToneMapping::normalized(&image);
+        assert!(mapping.quantize(&image) == vec![128, 128, 128, 255, 255, 255]);
+    }
+}